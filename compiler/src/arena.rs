@@ -0,0 +1,261 @@
+use crate::settings::{self, Settings, SettingsError};
+use crate::simulator::Pose;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A straight wall between two points, in centimetres. Walls have no
+/// thickness; the car collides when its footprint touches one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Wall {
+    pub from: [f64; 2],
+    pub to: [f64; 2],
+}
+
+/// An axis-aligned rectangle: a box the car must not hit, or a goal it
+/// should reach. `x` and `y` are the corner with the smallest coordinates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Rect {
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        x >= self.x && x <= self.x + self.width && y >= self.y && y <= self.y + self.height
+    }
+
+    /// Distance from a point to the nearest point of the rectangle, zero
+    /// inside it.
+    fn distance(&self, x: f64, y: f64) -> f64 {
+        let dx = (self.x - x).max(0.0).max(x - (self.x + self.width));
+        let dy = (self.y - y).max(0.0).max(y - (self.y + self.height));
+        dx.hypot(dy)
+    }
+}
+
+/// A region the car should drive into, reported the first time its center
+/// is inside.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Goal {
+    pub name: String,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Goal {
+    pub fn area(&self) -> Rect {
+        Rect {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// A map for the simulator: where the car starts, what it can hit and where
+/// it should go. Coordinates are centimetres with y pointing up, and the
+/// start heading is in degrees counter-clockwise from the x axis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Arena {
+    pub name: String,
+    pub start: Pose,
+    /// The car is modelled as a circle of this radius around its position
+    pub robot_radius: f64,
+    pub walls: Vec<Wall>,
+    pub boxes: Vec<Rect>,
+    pub goals: Vec<Goal>,
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Arena {
+            name: "arena".to_string(),
+            start: Pose::default(),
+            robot_radius: 10.0,
+            walls: Vec::new(),
+            boxes: Vec::new(),
+            goals: Vec::new(),
+        }
+    }
+}
+
+impl Arena {
+    pub fn from_toml(source: &str) -> Result<Self, SettingsError> {
+        settings::from_toml(source)
+    }
+
+    pub fn from_json(source: &str) -> Result<Self, SettingsError> {
+        settings::from_json(source)
+    }
+
+    /// Loads a arena, picking the format from the file extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SettingsError> {
+        settings::load(path.as_ref())
+    }
+
+    /// Checks that sizes are positive, goal names are unique and the car
+    /// does not start inside an obstacle.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if !(self.robot_radius.is_finite() && self.robot_radius > 0.0) {
+            return Err(Self::invalid(format!(
+                "robot_radius must be a positive number, found {}",
+                self.robot_radius
+            )));
+        }
+
+        let areas = self
+            .boxes
+            .iter()
+            .enumerate()
+            .map(|(i, area)| (format!("box {}", i + 1), area.clone()))
+            .chain(
+                self.goals
+                    .iter()
+                    .map(|goal| (format!("goal '{}'", goal.name), goal.area())),
+            );
+        for (name, area) in areas {
+            if !(area.width > 0.0 && area.height > 0.0) {
+                return Err(Self::invalid(format!(
+                    "{} must have a positive width and height",
+                    name
+                )));
+            }
+        }
+
+        for (i, goal) in self.goals.iter().enumerate() {
+            if self.goals[..i].iter().any(|other| other.name == goal.name) {
+                return Err(Self::invalid(format!(
+                    "Goal '{}' is defined twice",
+                    goal.name
+                )));
+            }
+        }
+
+        if let Some(obstacle) = self.obstacle_at(self.start.x, self.start.y) {
+            return Err(Self::invalid(format!(
+                "The car starts touching {}",
+                obstacle
+            )));
+        }
+        Ok(())
+    }
+
+    /// The first obstacle the car's footprint touches with its center at
+    /// `x`, `y`, named as in collision reports: `wall 1`, `box 2`.
+    pub fn obstacle_at(&self, x: f64, y: f64) -> Option<String> {
+        let walls = self.walls.iter().enumerate().find_map(|(i, wall)| {
+            (segment_distance(wall, x, y) < self.robot_radius).then(|| format!("wall {}", i + 1))
+        });
+        walls.or_else(|| {
+            self.boxes.iter().enumerate().find_map(|(i, area)| {
+                (area.distance(x, y) < self.robot_radius).then(|| format!("box {}", i + 1))
+            })
+        })
+    }
+}
+
+impl Settings for Arena {
+    const WHAT: &'static str = "arena";
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        Arena::validate(self)
+    }
+}
+
+/// Distance from a point to the nearest point of a wall.
+fn segment_distance(wall: &Wall, x: f64, y: f64) -> f64 {
+    let [x1, y1] = wall.from;
+    let [x2, y2] = wall.to;
+    let (dx, dy) = (x2 - x1, y2 - y1);
+    let length = dx * dx + dy * dy;
+    let t = if length == 0.0 {
+        0.0
+    } else {
+        (((x - x1) * dx + (y - y1) * dy) / length).clamp(0.0, 1.0)
+    };
+    (x - (x1 + t * dx)).hypot(y - (y1 + t * dy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toml_arena() {
+        let arena = Arena::from_toml(
+            r#"
+            name = "corridor"
+
+            [start]
+            heading = 90.0
+
+            [[walls]]
+            from = [-30.0, -30.0]
+            to = [-30.0, 200.0]
+
+            [[boxes]]
+            x = 20.0
+            y = 50.0
+            width = 20.0
+            height = 20.0
+
+            [[goals]]
+            name = "end"
+            x = -20.0
+            y = 180.0
+            width = 40.0
+            height = 20.0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(arena.start.heading, 90.0);
+        assert_eq!(arena.start.x, 0.0);
+        assert_eq!(arena.robot_radius, 10.0);
+        assert!(arena.goals[0].area().contains(0.0, 190.0));
+        assert_eq!(arena.obstacle_at(-25.0, 100.0), Some("wall 1".to_string()));
+        assert_eq!(arena.obstacle_at(15.0, 60.0), Some("box 1".to_string()));
+        assert_eq!(arena.obstacle_at(0.0, 100.0), None);
+    }
+
+    #[test]
+    fn test_invalid_arenas() {
+        let error =
+            Arena::from_json(r#"{ "walls": [{ "from": [-5.0, -20.0], "to": [-5.0, 20.0] }] }"#)
+                .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid arena: The car starts touching wall 1"
+        );
+
+        let error = Arena::from_json(
+            r#"{ "goals": [
+                { "name": "a", "x": 50.0, "y": 0.0, "width": 10.0, "height": 10.0 },
+                { "name": "a", "x": 80.0, "y": 0.0, "width": 10.0, "height": 10.0 }
+            ] }"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid arena: Goal 'a' is defined twice"
+        );
+
+        assert!(matches!(
+            Arena::from_toml("[[boxes]]\nx = 50.0\ny = 0.0\nwidth = 0.0\nheight = 5.0"),
+            Err(SettingsError::Invalid { .. })
+        ));
+        assert!(matches!(
+            Arena::from_toml("walls = 3"),
+            Err(SettingsError::Toml { .. })
+        ));
+    }
+}
@@ -0,0 +1,330 @@
+use crate::diagnostic::{Diagnostic, Stage};
+use crate::ir::{Command, Operand, Program};
+use crate::span::Span;
+use std::collections::HashMap;
+
+/// Deepest chain of section calls, counting `main` itself, before we warn.
+///
+/// Every call pushes a return address, saved registers and the callee's
+/// locals onto the ATmega328's stack, which shares its 2 KB of SRAM with
+/// globals and the Arduino core, so deep chains are worth flagging.
+pub const MAX_CALL_DEPTH: usize = 16;
+
+/// Which sections call which, built from the `jal` instructions in each
+/// section (including those inside `repeat` blocks).
+#[derive(Debug)]
+pub struct CallGraph {
+    names: Vec<String>,
+    /// For each section, the sections it calls and where
+    calls: Vec<Vec<(usize, Span)>>,
+    /// Whether the section can stop recursing: it has a conditional branch
+    /// or a loop whose count is only known at run time
+    conditional: Vec<bool>,
+}
+
+impl CallGraph {
+    /// Builds the graph. Calls to undefined sections are ignored; the
+    /// semantic pass reports those.
+    pub fn new(program: &Program) -> Self {
+        let mut indices = HashMap::new();
+        for (index, section) in program.sections.iter().enumerate() {
+            indices.entry(section.name.as_str()).or_insert(index);
+        }
+
+        let mut calls = Vec::new();
+        let mut conditional = Vec::new();
+        for section in &program.sections {
+            let mut edges = Vec::new();
+            let mut has_condition = false;
+            collect_calls(&section.commands, &indices, &mut edges, &mut has_condition);
+            calls.push(edges);
+            conditional.push(has_condition);
+        }
+
+        CallGraph {
+            names: program.sections.iter().map(|s| s.name.clone()).collect(),
+            calls,
+            conditional,
+        }
+    }
+
+    /// Groups of sections that call each other recursively, as indices into
+    /// the program's sections. A section that calls itself is a group of one.
+    pub fn cycles(&self) -> Vec<Vec<usize>> {
+        self.components()
+            .into_iter()
+            .filter(|component| {
+                component.len() > 1
+                    || self.calls[component[0]]
+                        .iter()
+                        .any(|&(callee, _)| callee == component[0])
+            })
+            .collect()
+    }
+
+    /// The longest chain of calls starting at `root`, counting `root` itself.
+    /// Each recursive group counts once, since its real depth is unbounded.
+    pub fn max_depth(&self, root: &str) -> Option<usize> {
+        let root = self.names.iter().position(|name| name == root)?;
+        let components = self.components();
+        let mut component_of = vec![0; self.names.len()];
+        for (id, component) in components.iter().enumerate() {
+            for &section in component {
+                component_of[section] = id;
+            }
+        }
+
+        let mut memo = vec![None; components.len()];
+        Some(self.component_depth(component_of[root], &components, &component_of, &mut memo))
+    }
+
+    /// How many frames running `root` can need at once: its deepest call
+    /// chain, or at least `MAX_CALL_DEPTH` when any section recurses, since
+    /// the real depth then depends on the data.
+    pub fn stack_size(&self, root: &str) -> usize {
+        let depth = self.max_depth(root).unwrap_or(0);
+        if self.cycles().is_empty() {
+            depth
+        } else {
+            depth.max(MAX_CALL_DEPTH)
+        }
+    }
+
+    fn component_depth(
+        &self,
+        id: usize,
+        components: &[Vec<usize>],
+        component_of: &[usize],
+        memo: &mut Vec<Option<usize>>,
+    ) -> usize {
+        if let Some(depth) = memo[id] {
+            return depth;
+        }
+        let mut deepest = 0;
+        for &section in &components[id] {
+            for &(callee, _) in &self.calls[section] {
+                let callee = component_of[callee];
+                if callee != id {
+                    deepest =
+                        deepest.max(self.component_depth(callee, components, component_of, memo));
+                }
+            }
+        }
+        memo[id] = Some(deepest + 1);
+        deepest + 1
+    }
+
+    /// Strongly connected components, found with Tarjan's algorithm.
+    fn components(&self) -> Vec<Vec<usize>> {
+        let mut tarjan = Tarjan {
+            graph: self,
+            index: 0,
+            indices: vec![None; self.names.len()],
+            lowlinks: vec![0; self.names.len()],
+            stack: Vec::new(),
+            on_stack: vec![false; self.names.len()],
+            components: Vec::new(),
+        };
+        for section in 0..self.names.len() {
+            if tarjan.indices[section].is_none() {
+                tarjan.visit(section);
+            }
+        }
+        tarjan.components
+    }
+}
+
+struct Tarjan<'a> {
+    graph: &'a CallGraph,
+    index: usize,
+    indices: Vec<Option<usize>>,
+    lowlinks: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, section: usize) {
+        self.indices[section] = Some(self.index);
+        self.lowlinks[section] = self.index;
+        self.index += 1;
+        self.stack.push(section);
+        self.on_stack[section] = true;
+
+        for &(callee, _) in &self.graph.calls[section] {
+            match self.indices[callee] {
+                None => {
+                    self.visit(callee);
+                    self.lowlinks[section] = self.lowlinks[section].min(self.lowlinks[callee]);
+                }
+                Some(index) if self.on_stack[callee] => {
+                    self.lowlinks[section] = self.lowlinks[section].min(index);
+                }
+                Some(_) => {}
+            }
+        }
+
+        if Some(self.lowlinks[section]) == self.indices[section] {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == section {
+                    break;
+                }
+            }
+            component.sort_unstable();
+            self.components.push(component);
+        }
+    }
+}
+
+fn collect_calls(
+    commands: &[Command],
+    indices: &HashMap<&str, usize>,
+    edges: &mut Vec<(usize, Span)>,
+    has_condition: &mut bool,
+) {
+    for command in commands {
+        match command {
+            Command::Jump { label, span } => {
+                if let Some(&callee) = indices.get(label.as_str()) {
+                    edges.push((callee, *span));
+                }
+            }
+            Command::Branch { .. } => *has_condition = true,
+            Command::Repeat { count, body, .. } => {
+                if matches!(count, Operand::Variable(_)) {
+                    *has_condition = true;
+                }
+                collect_calls(body, indices, edges, has_condition);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reports recursion and call chains too deep for the robot's stack.
+///
+/// Recursion with no conditional branch anywhere in the cycle can never
+/// stop and is an error; otherwise it is a warning, since we cannot tell
+/// statically whether the condition bounds it.
+pub fn analyze_calls(program: &Program) -> Vec<Diagnostic> {
+    let graph = CallGraph::new(program);
+    let mut diagnostics = Vec::new();
+
+    for cycle in graph.cycles() {
+        // Point at the first call that stays inside the cycle
+        let span = cycle
+            .iter()
+            .flat_map(|&section| &graph.calls[section])
+            .find(|(callee, _)| cycle.contains(callee))
+            .map(|&(_, span)| span)
+            .unwrap_or_default();
+
+        let names: Vec<String> = cycle
+            .iter()
+            .map(|&section| format!("'{}'", graph.names[section]))
+            .collect();
+        let description = if cycle.len() == 1 {
+            format!("Section {} calls itself", names[0])
+        } else {
+            format!("Sections {} call each other", names.join(", "))
+        };
+
+        if cycle.iter().any(|&section| graph.conditional[section]) {
+            diagnostics.push(Diagnostic::warning(
+                Stage::Semantic,
+                format!(
+                    "{}; make sure the recursion is bounded, the robot only has 2 KB of stack",
+                    description
+                ),
+                span,
+            ));
+        } else {
+            diagnostics.push(Diagnostic::error(
+                Stage::Semantic,
+                format!(
+                    "{} with no way to stop, which overflows the robot's stack",
+                    description
+                ),
+                span,
+            ));
+        }
+    }
+
+    if let Some(depth) = graph.max_depth("main") {
+        if depth > MAX_CALL_DEPTH {
+            let span = program
+                .sections
+                .iter()
+                .find(|section| section.name == "main")
+                .map(|section| section.span)
+                .unwrap_or_default();
+            diagnostics.push(Diagnostic::warning(
+                Stage::Semantic,
+                format!(
+                    "Calls from 'main' nest {} sections deep, more than the recommended {}",
+                    depth, MAX_CALL_DEPTH
+                ),
+                span,
+            ));
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn graph(input: &str) -> CallGraph {
+        let program = Parser::new(input.to_string()).parse().unwrap();
+        CallGraph::new(&program)
+    }
+
+    #[test]
+    fn test_call_graph_depth() {
+        let graph = graph(
+            r#"
+            leaf:
+                mov forward, 1
+            middle:
+                jal leaf
+            other:
+                repeat 2:
+                    jal middle
+                end
+            main:
+                jal leaf
+                jal other
+            "#,
+        );
+
+        assert!(graph.cycles().is_empty());
+        assert_eq!(graph.max_depth("main"), Some(4));
+        assert_eq!(graph.max_depth("leaf"), Some(1));
+        assert_eq!(graph.max_depth("missing"), None);
+    }
+
+    #[test]
+    fn test_call_graph_cycles() {
+        let graph = graph(
+            r#"
+            ping:
+                jal pong
+            pong:
+                jal ping
+            main:
+                jal main
+                jal ping
+            "#,
+        );
+
+        assert_eq!(graph.cycles(), vec![vec![0, 1], vec![2]]);
+        assert_eq!(graph.max_depth("main"), Some(2));
+    }
+}
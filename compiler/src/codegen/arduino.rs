@@ -0,0 +1,344 @@
+use super::sketch::{self, comparison, operand, variable_name};
+use super::{generate, Backend};
+use crate::ir::{Command, MoveKind, Operand, Program, Section, Unit};
+use crate::profile::RobotProfile;
+
+pub fn generate_arduino_code(program: &Program) -> Result<String, String> {
+    generate(&ArduinoBackend::default(), program)
+}
+
+/// Generates an Arduino sketch wired and timed for a robot profile, whose
+/// moves call blocking helper functions built on `delay()`. See
+/// `StateMachineBackend` for a sketch that keeps running while it moves.
+#[derive(Debug, Default)]
+pub struct ArduinoBackend {
+    profile: RobotProfile,
+}
+
+impl ArduinoBackend {
+    pub fn new(profile: RobotProfile) -> Self {
+        ArduinoBackend { profile }
+    }
+
+    fn duration_ms(&self, amount: &Operand, unit: Option<Unit>) -> Result<String, String> {
+        sketch::duration_ms(&self.profile, amount, &operand(amount), unit)
+    }
+}
+
+impl Backend for ArduinoBackend {
+    fn name(&self) -> &'static str {
+        "arduino"
+    }
+
+    fn file_extension(&self) -> &'static str {
+        "ino"
+    }
+
+    fn emit_prelude(&self, output: &mut String, program: &Program) -> Result<(), String> {
+        sketch::emit_globals(output, program, &self.profile);
+        sketch::emit_setup(output, &self.profile);
+        output.push_str(
+            r#"void loop() {
+    main_loop();
+}
+
+"#,
+        );
+        Ok(())
+    }
+
+    fn emit_section(&self, output: &mut String, section: &Section) -> Result<(), String> {
+        output.push_str(&format!("void {}() {{\n", function_name(&section.name)));
+
+        // Locals are declared up front so a goto never jumps past an
+        // initialization, which C++ rejects
+        declare_locals(output, &section.commands);
+        self.emit_commands(output, section, &section.commands, 1)?;
+
+        output.push_str("}\n\n");
+        Ok(())
+    }
+
+    fn emit_command(
+        &self,
+        output: &mut String,
+        section: &Section,
+        command: &Command,
+        depth: usize,
+    ) -> Result<(), String> {
+        let indent = "    ".repeat(depth);
+
+        match command {
+            Command::Move {
+                r#type,
+                amount,
+                unit: Some(unit),
+                ..
+            } => {
+                let helper = match r#type {
+                    MoveKind::Forward => "forward_ms",
+                    MoveKind::Backward => "backwards_ms",
+                    MoveKind::Left => "turn_left_ms",
+                    MoveKind::Right => "turn_right_ms",
+                    MoveKind::Wait => "wait_ms",
+                    _ => return Err(format!("'mov {}' does not take a unit", r#type)),
+                };
+                let duration = self.duration_ms(amount, Some(*unit))?;
+                if *unit == Unit::Ms {
+                    output.push_str(&format!("{}{}({});\n", indent, helper, duration));
+                } else {
+                    // Keep the original amount visible next to the converted time
+                    output.push_str(&format!(
+                        "{}{}({}); // {}{}\n",
+                        indent, helper, duration, amount, unit
+                    ));
+                }
+            }
+            Command::Move { r#type, amount, .. } => match r#type {
+                MoveKind::Forward => {
+                    output.push_str(&format!("{}forward({});\n", indent, operand(amount)));
+                }
+                MoveKind::Backward => {
+                    output.push_str(&format!("{}backwards({});\n", indent, operand(amount)));
+                }
+                MoveKind::Left => {
+                    output.push_str(&format!(
+                        "{}turn_left_ms({});\n",
+                        indent,
+                        self.duration_ms(amount, None)?
+                    ));
+                }
+                MoveKind::Right => {
+                    output.push_str(&format!(
+                        "{}turn_right_ms({});\n",
+                        indent,
+                        self.duration_ms(amount, None)?
+                    ));
+                }
+                MoveKind::Direction => match amount {
+                    Operand::Literal(1) => output.push_str(&format!("{}left();\n", indent)),
+                    Operand::Literal(2) => output.push_str(&format!("{}right();\n", indent)),
+                    Operand::Literal(0) => output.push_str(&format!("{}straight();\n", indent)),
+                    Operand::Literal(n) => return Err(format!("Invalid direction value: {}", n)),
+                    Operand::Variable(_) => {
+                        output.push_str(&format!("{}steer({});\n", indent, operand(amount)));
+                    }
+                },
+                MoveKind::Wait => {
+                    output.push_str(&format!("{}wait({});\n", indent, operand(amount)));
+                }
+                MoveKind::Speed => {
+                    output.push_str(&format!("{}speed({});\n", indent, operand(amount)));
+                }
+            },
+            Command::Jump { label, .. } => {
+                output.push_str(&format!("{}{}();\n", indent, function_name(label)));
+            }
+            Command::Var { name, value, .. } => {
+                output.push_str(&format!(
+                    "{}{} = {};\n",
+                    indent,
+                    variable_name(name),
+                    operand(value)
+                ));
+            }
+            Command::Label { name, .. } => {
+                output.push_str(&format!("{}:;\n", label_name(name)));
+            }
+            Command::Goto { label, .. } => {
+                output.push_str(&format!("{}goto {};\n", indent, label_name(label)));
+            }
+            Command::Branch {
+                condition,
+                left,
+                right,
+                label,
+                ..
+            } => {
+                output.push_str(&format!(
+                    "{}if ({} {} {}) goto {};\n",
+                    indent,
+                    operand(left),
+                    comparison(*condition),
+                    operand(right),
+                    label_name(label)
+                ));
+            }
+            Command::Add {
+                variable, value, ..
+            } => {
+                let name = variable_name(variable);
+                output.push_str(&format!(
+                    "{}{} = wrapping_add({}, {});\n",
+                    indent,
+                    name,
+                    name,
+                    operand(value)
+                ));
+            }
+            Command::Sub {
+                variable, value, ..
+            } => {
+                let name = variable_name(variable);
+                output.push_str(&format!(
+                    "{}{} = wrapping_sub({}, {});\n",
+                    indent,
+                    name,
+                    name,
+                    operand(value)
+                ));
+            }
+            Command::Repeat { count, body, .. } => {
+                // Count down from the starting value so the count is only
+                // read once, even if the body changes the variable
+                let counter = format!("repeat_{}", depth);
+                output.push_str(&format!(
+                    "{}for (long {} = {}; {} > 0; {}--) {{\n",
+                    indent,
+                    counter,
+                    operand(count),
+                    counter,
+                    counter
+                ));
+                self.emit_commands(output, section, body, depth + 1)?;
+                output.push_str(&format!("{}}}\n", indent));
+            }
+            Command::Return { .. } => {
+                if section.name == "main" {
+                    // loop() calls main_loop() again, so this restarts the program
+                    output.push_str(&format!("{}return; // restart main\n", indent));
+                } else {
+                    output.push_str(&format!("{}return;\n", indent));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn emit_epilogue(&self, output: &mut String, _program: &Program) -> Result<(), String> {
+        let settle = self.profile.timing.settle_ms;
+        let turn = self.profile.timing.turn_ms;
+
+        // Add the motor control functions
+        output.push_str(&format!(
+            r#"void forward(long time){{
+	forward_ms(seconds_ms(time));
+}}
+
+void forward_ms(unsigned long time){{
+        	delay({settle});
+	digitalWrite(in2, HIGH);
+	digitalWrite(in1, LOW);
+	delay(time);
+	digitalWrite(in1, LOW);
+	digitalWrite(in2, LOW);
+	delay({settle});
+
+}}
+
+void backwards(long time){{
+	backwards_ms(seconds_ms(time));
+}}
+
+void backwards_ms(unsigned long time){{
+	delay({settle});
+	digitalWrite(in1, HIGH);
+	digitalWrite(in2, LOW);
+	delay(time);
+	digitalWrite(in1, LOW);
+	digitalWrite(in2, LOW);
+	delay({settle});
+}}
+
+// A turn steers to full lock and drives forward, like `mov direction`
+// followed by `mov forward`, then straightens up
+void turn_left_ms(unsigned long time){{
+	left();
+	forward_ms(time);
+	straight();
+}}
+
+void turn_right_ms(unsigned long time){{
+	right();
+	forward_ms(time);
+	straight();
+}}
+
+void right(){{
+    	straight();
+	delay({settle});
+	digitalWrite(in3, LOW);
+	digitalWrite(in4, HIGH);
+	delay({turn});
+}}
+
+void wait(long time){{
+	wait_ms(seconds_ms(time));
+}}
+
+void wait_ms(unsigned long time){{
+    	digitalWrite(in1, LOW);
+	digitalWrite(in2, LOW);
+	delay(time);
+}}
+
+void left(){{
+    	straight();
+	delay({settle});
+	digitalWrite(in4, LOW);
+	digitalWrite(in3, HIGH);
+	delay({turn});
+}}
+
+void straight(){{
+    	digitalWrite(in4, LOW);
+	digitalWrite(in3, LOW);
+	delay({turn});
+}}
+
+void steer(long value){{
+	if (value == 1) {{
+		left();
+	}} else if (value == 2) {{
+		right();
+	}} else {{
+		straight();
+	}}
+}}
+
+"#
+        ));
+        sketch::emit_helpers(output, &self.profile);
+
+        Ok(())
+    }
+}
+
+fn declare_locals(output: &mut String, commands: &[Command]) {
+    for command in commands {
+        match command {
+            Command::Var { name, .. } => {
+                output.push_str(&format!("    long {} = 0;\n", variable_name(name)));
+            }
+            Command::Repeat { body, .. } => declare_locals(output, body),
+            _ => {}
+        }
+    }
+}
+
+/// C++ name for a section's function. `main` becomes `main_loop`, which
+/// `loop()` calls; the others are prefixed so no section can collide with
+/// the helpers, the Arduino core or a C++ keyword.
+fn function_name(section: &str) -> String {
+    if section == "main" {
+        "main_loop".to_string()
+    } else {
+        format!("section_{}", section)
+    }
+}
+
+/// C++ name for a local label, prefixed so labels like `.for` stay valid.
+fn label_name(name: &str) -> String {
+    format!("label_{}", name)
+}
@@ -0,0 +1,115 @@
+mod arduino;
+mod sketch;
+mod state_machine;
+
+use crate::ir::{Command, Program, Section};
+use crate::profile::RobotProfile;
+
+pub use arduino::{generate_arduino_code, ArduinoBackend};
+pub use state_machine::StateMachineBackend;
+
+/// A code generation target.
+///
+/// `generate` drives a backend over a checked program: the prelude, then
+/// every section in order, then the epilogue. Backends append their output
+/// to a shared `String` and report unsupported programs as an error message.
+pub trait Backend {
+    /// The name the target is selected by, e.g. in `compile_to`.
+    fn name(&self) -> &'static str;
+
+    /// Extension of the generated file, without the leading dot.
+    fn file_extension(&self) -> &'static str;
+
+    /// Emits everything that comes before the first section.
+    fn emit_prelude(&self, output: &mut String, program: &Program) -> Result<(), String>;
+
+    /// Emits a whole section, usually by calling `emit_commands` on its body.
+    fn emit_section(&self, output: &mut String, section: &Section) -> Result<(), String>;
+
+    /// Emits a single command, indented `depth` levels deep. Commands with a
+    /// body, like `repeat`, emit it with `emit_commands` at `depth + 1`.
+    ///
+    /// Backends that lower whole sections before emitting them, like
+    /// `StateMachineBackend`, have no single commands to emit and keep this
+    /// default, which refuses.
+    fn emit_command(
+        &self,
+        _output: &mut String,
+        _section: &Section,
+        command: &Command,
+        _depth: usize,
+    ) -> Result<(), String> {
+        Err(format!(
+            "The {} backend cannot emit the command at {} on its own",
+            self.name(),
+            command.span()
+        ))
+    }
+
+    /// Emits everything that comes after the last section.
+    fn emit_epilogue(&self, output: &mut String, program: &Program) -> Result<(), String>;
+
+    fn emit_commands(
+        &self,
+        output: &mut String,
+        section: &Section,
+        commands: &[Command],
+        depth: usize,
+    ) -> Result<(), String> {
+        for command in commands {
+            self.emit_command(output, section, command, depth)?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings shared by every backend.
+#[derive(Debug, Clone, Default)]
+pub struct CodegenOptions {
+    /// The robot the code is generated for
+    pub profile: RobotProfile,
+    /// Generate code that never blocks while the robot moves, for targets
+    /// that support it
+    pub non_blocking: bool,
+}
+
+/// Names of every built-in target, in the order they are listed to users.
+pub const TARGETS: &[&str] = &["arduino"];
+
+/// Looks up a built-in backend by its target name and configures it with
+/// `options`.
+pub fn backend(target: &str, options: &CodegenOptions) -> Option<Box<dyn Backend>> {
+    match target {
+        "arduino" if options.non_blocking => {
+            Some(Box::new(StateMachineBackend::new(options.profile.clone())))
+        }
+        "arduino" => Some(Box::new(ArduinoBackend::new(options.profile.clone()))),
+        _ => None,
+    }
+}
+
+/// Generates the code for `program` with the given backend.
+pub fn generate(backend: &dyn Backend, program: &Program) -> Result<String, String> {
+    let mut output = String::new();
+    backend.emit_prelude(&mut output, program)?;
+    for section in &program.sections {
+        backend.emit_section(&mut output, section)?;
+    }
+    backend.emit_epilogue(&mut output, program)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_target_has_a_backend() {
+        for target in TARGETS {
+            let backend =
+                backend(target, &CodegenOptions::default()).expect("listed target has a backend");
+            assert_eq!(backend.name(), *target);
+        }
+        assert!(backend("z80", &CodegenOptions::default()).is_none());
+    }
+}
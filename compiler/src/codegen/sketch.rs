@@ -0,0 +1,177 @@
+//! Parts shared by the blocking and the non-blocking Arduino sketches: the
+//! pin definitions, program variables and `setup()`, the conversion of
+//! `mov` amounts into times, and the helpers both sketches call.
+
+use crate::ir::{Condition, Operand, Program, Unit};
+use crate::profile::RobotProfile;
+
+/// Emits the motor pin definitions and the program's global variables.
+pub(super) fn emit_globals(output: &mut String, program: &Program, profile: &RobotProfile) {
+    let drive = &profile.drive;
+    let steering = &profile.steering;
+    let (in1, in2) = drive.input_pins();
+    let (in3, in4) = steering.input_pins();
+
+    // Add the motor pin definitions. Inverted motors get their inputs
+    // swapped here, so the helpers never change
+    output.push_str(&format!(
+        r#"// Automatically Generated From IR
+// Motor A connections
+int enA = {};
+int in1 = {};
+int in2 = {};
+// Motor B connections
+int enB = {};
+int in3 = {};
+int in4 = {};
+
+"#,
+        drive.enable, in1, in2, steering.enable, in3, in4
+    ));
+
+    // Variables are `long` so they keep the IR's 32-bit range on the Uno,
+    // where `int` is only 16 bits
+    if !program.globals.is_empty() {
+        output.push_str("// Program variables\n");
+        for variable in &program.globals {
+            output.push_str(&format!(
+                "long {} = {};\n",
+                variable_name(&variable.name),
+                operand(&variable.value)
+            ));
+        }
+        output.push('\n');
+    }
+}
+
+/// Emits a `setup()` that makes every motor pin an output and stops both
+/// motors.
+pub(super) fn emit_setup(output: &mut String, profile: &RobotProfile) {
+    output.push_str(&format!(
+        r#"void setup() {{
+    // Set all the motor control pins to outputs
+    pinMode(enA, OUTPUT);
+    pinMode(enB, OUTPUT);
+    pinMode(in1, OUTPUT);
+    pinMode(in2, OUTPUT);
+    pinMode(in3, OUTPUT);
+    pinMode(in4, OUTPUT);
+    
+    // Turn off motors - Initial state
+    digitalWrite(in1, LOW);
+    digitalWrite(in2, LOW);
+    digitalWrite(in3, LOW);
+    digitalWrite(in4, LOW);
+    analogWrite(enA, {});
+    analogWrite(enB, {});
+}}
+
+"#,
+        profile.drive.duty, profile.steering.duty
+    ));
+}
+
+/// Emits the speed and arithmetic helpers.
+pub(super) fn emit_helpers(output: &mut String, profile: &RobotProfile) {
+    output.push_str(&format!(
+        r#"// Signed overflow is undefined in C++, so arithmetic goes through unsigned
+// long to get the same 32-bit wrapping as the IR
+long wrapping_add(long a, long b){{
+	return (long)((unsigned long)a + (unsigned long)b);
+}}
+
+long wrapping_sub(long a, long b){{
+	return (long)((unsigned long)a - (unsigned long)b);
+}}
+
+// Speed is a percentage of the profile's duty cycle. Literals are checked
+// by the compiler, variables are clamped here
+long current_speed = 100;
+
+void speed(long percent){{
+	percent = constrain(percent, 0, 100);
+	current_speed = percent;
+	analogWrite(enA, percent * {} / 100);
+	analogWrite(enB, percent * {} / 100);
+}}
+
+// Variable amounts are whole seconds. Negative ones move for no time at
+// all, as in the simulator
+unsigned long seconds_ms(long time){{
+	if (time < 0) {{
+		return 0;
+	}}
+	return (unsigned long)time * 1000UL;
+}}
+
+// Distances and angles are calibrated at full power, so cover them at a
+// lower speed by driving for longer. At speed 0 the car cannot get there
+// and only waits the full-power time
+unsigned long at_speed(unsigned long time){{
+	if (current_speed == 0) {{
+		return time;
+	}}
+	return time * 100 / current_speed;
+}}
+"#,
+        profile.drive.duty, profile.steering.duty
+    ));
+}
+
+/// C++ expression for how long a move lasts, in milliseconds. `value` is
+/// the C++ expression for the amount. Amounts with a unit are converted
+/// with the profile's calibration here, so the sketch only ever sees times;
+/// distances and angles are stretched by `at_speed` for the speed at the
+/// time.
+pub(super) fn duration_ms(
+    profile: &RobotProfile,
+    amount: &Operand,
+    value: &str,
+    unit: Option<Unit>,
+) -> Result<String, String> {
+    match (amount, unit) {
+        (Operand::Literal(n), unit) => {
+            let ms = profile.duration_ms(*n, unit);
+            if u32::try_from(ms).is_err() {
+                return Err(format!(
+                    "'{}{}' is {} ms, which does not fit in an unsigned long",
+                    n,
+                    unit.map_or("", Unit::name),
+                    ms
+                ));
+            }
+            match unit {
+                Some(Unit::Cm | Unit::Deg) => Ok(format!("at_speed({})", ms)),
+                _ => Ok(ms.to_string()),
+            }
+        }
+        (Operand::Variable(_), None) => Ok(format!("seconds_ms({})", value)),
+        (Operand::Variable(name), Some(unit)) => Err(format!(
+            "Unit '{}' cannot be used with variable '{}'",
+            unit, name
+        )),
+    }
+}
+
+/// The C++ operator a conditional branch compares with.
+pub(super) fn comparison(condition: Condition) -> &'static str {
+    match condition {
+        Condition::Equal => "==",
+        Condition::NotEqual => "!=",
+        Condition::Less => "<",
+        Condition::GreaterOrEqual => ">=",
+    }
+}
+
+/// C++ name for a program variable, prefixed so it cannot collide with the
+/// generated helpers or section functions.
+pub(super) fn variable_name(name: &str) -> String {
+    format!("var_{}", name)
+}
+
+pub(super) fn operand(operand: &Operand) -> String {
+    match operand {
+        Operand::Literal(n) => n.to_string(),
+        Operand::Variable(name) => variable_name(name),
+    }
+}
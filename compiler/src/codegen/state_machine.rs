@@ -0,0 +1,449 @@
+//! The non-blocking Arduino lowering.
+//!
+//! Every section becomes a step function over its lowered steps, driven one
+//! step per `loop()` call. Moves are split into phases that set the motor
+//! pins and then wait for a point in time measured with `millis()` instead
+//! of calling `delay()`, so code added to `loop()` keeps running while the
+//! robot moves. `jal` pushes a frame onto a fixed-size stack of sections
+//! rather than making a C++ call.
+
+use super::sketch::{self, comparison, operand, variable_name};
+use super::Backend;
+use crate::callgraph::CallGraph;
+use crate::ir::{MoveKind, Operand, Program, Section, Unit};
+use crate::lower::{lower, lower_section, Instruction, LoweredSection};
+use crate::profile::RobotProfile;
+
+/// C++ name of the step function for a section.
+fn step_name(section: &str) -> String {
+    format!("step_{}", section)
+}
+
+/// Generates an Arduino sketch wired and timed for a robot profile that
+/// runs the program as a `millis()`-based state machine, one step per
+/// `loop()` call, instead of blocking in `delay()` while the robot moves.
+///
+/// Sections are lowered to steps before they are emitted, so this backend
+/// never emits single commands.
+#[derive(Debug, Default)]
+pub struct StateMachineBackend {
+    profile: RobotProfile,
+}
+
+impl StateMachineBackend {
+    pub fn new(profile: RobotProfile) -> Self {
+        StateMachineBackend { profile }
+    }
+}
+
+impl Backend for StateMachineBackend {
+    fn name(&self) -> &'static str {
+        "arduino"
+    }
+
+    fn file_extension(&self) -> &'static str {
+        "ino"
+    }
+
+    fn emit_prelude(&self, output: &mut String, program: &Program) -> Result<(), String> {
+        sketch::emit_globals(output, program, &self.profile);
+        emit_runtime(output, program)?;
+        sketch::emit_setup(output, &self.profile);
+        emit_loop(output);
+        Ok(())
+    }
+
+    fn emit_section(&self, output: &mut String, section: &Section) -> Result<(), String> {
+        emit_section(output, section, &self.profile)
+    }
+
+    fn emit_epilogue(&self, output: &mut String, _program: &Program) -> Result<(), String> {
+        emit_helpers(output);
+        sketch::emit_helpers(output, &self.profile);
+        Ok(())
+    }
+}
+
+/// Emits the frame stack, the timing state and the prototypes of the
+/// helpers and step functions.
+fn emit_runtime(output: &mut String, program: &Program) -> Result<(), String> {
+    let sections = lower(program)?;
+
+    // Every frame has room for the largest section's locals and counters
+    let locals = sections
+        .iter()
+        .map(|section| section.locals.len() + section.counters)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let stack_size = CallGraph::new(program).stack_size("main");
+
+    output.push_str(&format!(
+        r#"// A running section: its step function, the next step to run and its
+// locals, followed by the counters of its repeat loops
+struct Frame {{
+    void (*run)(Frame &frame);
+    int step;
+    long locals[{}];
+}};
+
+const int STACK_SIZE = {};
+Frame stack[STACK_SIZE];
+int depth = 0;
+bool halted = false;
+
+// The current move phase lasts until wait_ms have passed since wait_start
+unsigned long wait_start = 0;
+unsigned long wait_ms = 0;
+
+// Prototypes are written out because the Arduino IDE does not generate
+// them for functions that take a Frame
+void wait_for(unsigned long ms);
+void call(void (*run)(Frame &frame));
+void ret();
+void halt();
+void drive_forward();
+void drive_backward();
+void drive_stop();
+void steer_left();
+void steer_right();
+void steer_straight();
+void speed(long percent);
+unsigned long seconds_ms(long time);
+unsigned long at_speed(unsigned long time);
+long wrapping_add(long a, long b);
+long wrapping_sub(long a, long b);
+"#,
+        locals, stack_size
+    ));
+
+    for section in &sections {
+        output.push_str(&format!(
+            "void {}(Frame &frame);\n",
+            step_name(&section.name)
+        ));
+    }
+    output.push('\n');
+    Ok(())
+}
+
+/// Emits a `loop()` that runs the current step once the previous one has
+/// finished waiting.
+fn emit_loop(output: &mut String) {
+    output.push_str(
+        r#"void loop() {
+    // Code added here, like reading a sensor or serial commands, runs
+    // between steps, even while the robot is moving
+    if (halted || millis() - wait_start < wait_ms) {
+        return;
+    }
+    wait_ms = 0;
+
+    // Like the blocking sketch, main starts over once it returns
+    if (depth == 0) {
+        call(step_main);
+    }
+    Frame &frame = stack[depth - 1];
+    frame.run(frame);
+}
+
+"#,
+    );
+}
+
+/// Emits the step function of a section: a `switch` with one `case` per
+/// move phase or instruction, each of which runs and returns to `loop()`.
+fn emit_section(
+    output: &mut String,
+    section: &Section,
+    profile: &RobotProfile,
+) -> Result<(), String> {
+    let lowered = lower_section(section)?;
+    let emitter = SectionEmitter {
+        section: &lowered,
+        profile,
+    };
+
+    // Moves take several cases, so jumps need the first case of each step
+    let mut phases = Vec::new();
+    for step in &lowered.steps {
+        phases.push(emitter.phases(&step.instruction)?);
+    }
+    let mut first_case = Vec::new();
+    let mut case = 0;
+    for step_phases in &phases {
+        first_case.push(case);
+        case += step_phases.len();
+    }
+
+    output.push_str(&format!(
+        "void {}(Frame &frame) {{\n    switch (frame.step++) {{\n",
+        step_name(&section.name)
+    ));
+    let mut case = 0;
+    for (step, step_phases) in lowered.steps.iter().zip(phases) {
+        for (index, phase) in step_phases.into_iter().enumerate() {
+            output.push_str(&format!("    case {}:\n", case));
+            if index == 0 && step.span.line > 0 {
+                output.push_str(&format!("        // line {}\n", step.span.line));
+            }
+            let phase = match phase {
+                Phase::Run(code) => code,
+                Phase::Jump { code, target } => {
+                    code.replace("{target}", &first_case[target].to_string())
+                }
+            };
+            for line in phase.lines() {
+                output.push_str(&format!("        {}\n", line));
+            }
+            output.push_str("        return;\n");
+            case += 1;
+        }
+    }
+    output.push_str("    }\n}\n\n");
+
+    Ok(())
+}
+
+/// The motor and timing helpers only the step functions call.
+fn emit_helpers(output: &mut String) {
+    output.push_str(
+        r#"// Starts a move phase: loop() runs the next step once `ms` have passed
+void wait_for(unsigned long ms){
+	wait_start = millis();
+	wait_ms = ms;
+}
+
+void call(void (*run)(Frame &frame)){
+	if (depth == STACK_SIZE) {
+		// Out of frames: stop the robot rather than overwrite memory
+		halt();
+		return;
+	}
+	Frame &frame = stack[depth++];
+	frame.run = run;
+	frame.step = 0;
+	for (int i = 0; i < (int)(sizeof(frame.locals) / sizeof(frame.locals[0])); i++) {
+		frame.locals[i] = 0;
+	}
+}
+
+void ret(){
+	depth--;
+}
+
+void halt(){
+	drive_stop();
+	steer_straight();
+	halted = true;
+}
+
+void drive_forward(){
+	digitalWrite(in2, HIGH);
+	digitalWrite(in1, LOW);
+}
+
+void drive_backward(){
+	digitalWrite(in1, HIGH);
+	digitalWrite(in2, LOW);
+}
+
+void drive_stop(){
+	digitalWrite(in1, LOW);
+	digitalWrite(in2, LOW);
+}
+
+void steer_left(){
+	digitalWrite(in4, LOW);
+	digitalWrite(in3, HIGH);
+}
+
+void steer_right(){
+	digitalWrite(in3, LOW);
+	digitalWrite(in4, HIGH);
+}
+
+void steer_straight(){
+	digitalWrite(in4, LOW);
+	digitalWrite(in3, LOW);
+}
+
+"#,
+    );
+}
+
+/// The code for one `case`. Jumps name their target step as `{target}`,
+/// which is replaced by the step's first case once every step is sized.
+enum Phase {
+    Run(String),
+    Jump { code: String, target: usize },
+}
+
+struct SectionEmitter<'a> {
+    section: &'a LoweredSection,
+    profile: &'a RobotProfile,
+}
+
+impl SectionEmitter<'_> {
+    fn phases(&self, instruction: &Instruction) -> Result<Vec<Phase>, String> {
+        let phase = |code: String| vec![Phase::Run(code)];
+
+        Ok(match instruction {
+            Instruction::Move { kind, amount, unit } => self.move_phases(*kind, amount, *unit)?,
+            Instruction::Set { variable, value } => phase(format!(
+                "{} = {};",
+                self.variable(variable),
+                self.operand(value)
+            )),
+            Instruction::Add { variable, value } => {
+                let name = self.variable(variable);
+                phase(format!(
+                    "{} = wrapping_add({}, {});",
+                    name,
+                    name,
+                    self.operand(value)
+                ))
+            }
+            Instruction::Sub { variable, value } => {
+                let name = self.variable(variable);
+                phase(format!(
+                    "{} = wrapping_sub({}, {});",
+                    name,
+                    name,
+                    self.operand(value)
+                ))
+            }
+            Instruction::Jump { target } => vec![Phase::Jump {
+                code: "frame.step = {target};".to_string(),
+                target: *target,
+            }],
+            Instruction::Branch {
+                condition,
+                left,
+                right,
+                target,
+            } => {
+                vec![Phase::Jump {
+                    code: format!(
+                        "if ({} {} {}) frame.step = {{target}};",
+                        self.operand(left),
+                        comparison(*condition),
+                        self.operand(right)
+                    ),
+                    target: *target,
+                }]
+            }
+            Instruction::Call { section } => phase(format!("call({});", step_name(section))),
+            Instruction::Return => phase("ret();".to_string()),
+            Instruction::StartLoop { counter, count } => phase(format!(
+                "{} = {};",
+                self.counter(*counter),
+                self.operand(count)
+            )),
+            Instruction::Loop { counter, exit } => {
+                let counter = self.counter(*counter);
+                vec![Phase::Jump {
+                    code: format!(
+                        "if ({} <= 0) frame.step = {{target}};\nelse {}--;",
+                        counter, counter
+                    ),
+                    target: *exit,
+                }]
+            }
+        })
+    }
+
+    /// Splits a move into the same pin changes and pauses as the blocking
+    /// helpers, one phase per pause.
+    fn move_phases(
+        &self,
+        kind: MoveKind,
+        amount: &Operand,
+        unit: Option<Unit>,
+    ) -> Result<Vec<Phase>, String> {
+        let settle = self.profile.timing.settle_ms;
+        let turn = self.profile.timing.turn_ms;
+        let straight = format!("steer_straight();\nwait_for({});", turn);
+        let steer = |side: &str| {
+            vec![
+                straight.clone(),
+                format!("wait_for({});", settle),
+                format!("steer_{}();\nwait_for({});", side, turn),
+            ]
+        };
+        let drive = |direction: &str, time: &str| {
+            vec![
+                format!("wait_for({});", settle),
+                format!("drive_{}();\nwait_for({});", direction, time),
+                format!("drive_stop();\nwait_for({});", settle),
+            ]
+        };
+
+        let code = match kind {
+            MoveKind::Forward => drive("forward", &self.duration_ms(amount, unit)?),
+            MoveKind::Backward => drive("backward", &self.duration_ms(amount, unit)?),
+            MoveKind::Wait => vec![format!(
+                "drive_stop();\nwait_for({});",
+                self.duration_ms(amount, unit)?
+            )],
+            MoveKind::Speed => vec![format!("speed({});", self.operand(amount))],
+            MoveKind::Direction => match amount {
+                Operand::Literal(0) => vec![straight],
+                Operand::Literal(1) => steer("left"),
+                Operand::Literal(2) => steer("right"),
+                Operand::Literal(n) => return Err(format!("Invalid direction value: {}", n)),
+                Operand::Variable(_) => {
+                    let value = self.operand(amount);
+                    vec![
+                        straight,
+                        format!(
+                            "if ({} == 1 || {} == 2) wait_for({});",
+                            value, value, settle
+                        ),
+                        format!(
+                            "if ({} == 1) steer_left();\nelse if ({} == 2) steer_right();\nif ({} == 1 || {} == 2) wait_for({});",
+                            value, value, value, value, turn
+                        ),
+                    ]
+                }
+            },
+            MoveKind::Left | MoveKind::Right => {
+                let side = if kind == MoveKind::Left {
+                    "left"
+                } else {
+                    "right"
+                };
+                let mut phases = steer(side);
+                phases.extend(drive("forward", &self.duration_ms(amount, unit)?));
+                phases.push(straight);
+                phases
+            }
+        };
+
+        Ok(code.into_iter().map(Phase::Run).collect())
+    }
+
+    fn duration_ms(&self, amount: &Operand, unit: Option<Unit>) -> Result<String, String> {
+        sketch::duration_ms(self.profile, amount, &self.operand(amount), unit)
+    }
+
+    /// Locals live in the frame, so recursive sections each get their own.
+    fn variable(&self, name: &str) -> String {
+        match self.section.locals.iter().position(|local| local == name) {
+            Some(index) => format!("frame.locals[{}]", index),
+            None => variable_name(name),
+        }
+    }
+
+    fn counter(&self, counter: usize) -> String {
+        format!("frame.locals[{}]", self.section.locals.len() + counter)
+    }
+
+    fn operand(&self, value: &Operand) -> String {
+        match value {
+            Operand::Variable(name) => self.variable(name),
+            Operand::Literal(_) => operand(value),
+        }
+    }
+}
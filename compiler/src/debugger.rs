@@ -0,0 +1,453 @@
+use crate::diagnostic::Diagnostic;
+use crate::ir::Program;
+use crate::simulator::{Keyframe, Simulation, SimulationOptions, Simulator, StopReason};
+use serde::{Deserialize, Serialize};
+
+/// Where execution should pause: on a source line, or whenever a section
+/// is called.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Breakpoint {
+    Line(usize),
+    Section(String),
+}
+
+/// Why the debugger paused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseReason {
+    /// Before the first instruction of `main`
+    Entry,
+    /// A step command finished
+    Step,
+    /// A breakpoint was reached
+    Breakpoint,
+}
+
+/// A section on the call stack and the line it runs next.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StackFrame {
+    pub section: String,
+    pub line: usize,
+}
+
+/// A variable and its current value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebugVariable {
+    pub name: String,
+    pub value: i32,
+}
+
+/// A command to the debugger, as sent by an editor.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum DebugRequest {
+    /// Replaces every breakpoint
+    SetBreakpoints {
+        breakpoints: Vec<Breakpoint>,
+    },
+    /// Runs until a breakpoint or the end of the simulation
+    Continue,
+    /// Runs to the next line, following `jal` into the called section
+    StepIn,
+    /// Runs to the next line of this section, running a `jal` to completion
+    StepOver,
+    /// Runs until the current section returns
+    StepOut,
+    StackTrace,
+    /// The locals of a frame, counting from the innermost, and the globals
+    Variables {
+        #[serde(default)]
+        frame: usize,
+    },
+}
+
+/// The debugger's answer to a `DebugRequest`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DebugEvent {
+    /// Each breakpoint, and whether any instruction is on its line or its
+    /// section exists
+    Breakpoints {
+        breakpoints: Vec<Breakpoint>,
+        verified: Vec<bool>,
+    },
+    /// Execution paused before running `frame`'s line
+    Stopped {
+        reason: PauseReason,
+        frame: StackFrame,
+        state: Keyframe,
+    },
+    /// The simulation ended. Further steps keep reporting this
+    Terminated {
+        simulation: Simulation,
+    },
+    StackTrace {
+        frames: Vec<StackFrame>,
+    },
+    Variables {
+        locals: Vec<DebugVariable>,
+        globals: Vec<DebugVariable>,
+    },
+    /// A request that could not be carried out, or code that does not
+    /// compile
+    Error {
+        message: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        diagnostics: Vec<Diagnostic>,
+    },
+}
+
+/// How far a run command goes before pausing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepMode {
+    Continue,
+    In,
+    Over,
+    Out,
+}
+
+/// The next instruction to run: how deep the call stack is, and the
+/// section and line it is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    depth: usize,
+    section: usize,
+    line: usize,
+}
+
+/// Runs a checked program on the simulator one source line at a time.
+///
+/// The debugger starts paused on entry to `main`. Run commands execute
+/// instructions until execution reaches a new line, that is, the line or
+/// the call depth of the next instruction changes, and the mode or a
+/// breakpoint says to pause there. Moves take their full simulated time,
+/// so a step over `mov forward, 2` advances the clock by the drive time
+/// and both settle delays.
+pub struct Debugger {
+    simulator: Simulator,
+    breakpoints: Vec<Breakpoint>,
+}
+
+impl Debugger {
+    /// Starts on a checked program; `compiler::debug` is the public way in.
+    pub(crate) fn new(program: &Program, options: &SimulationOptions) -> Self {
+        Debugger {
+            simulator: Simulator::new(program, options.clone()),
+            breakpoints: Vec::new(),
+        }
+    }
+
+    /// Replaces the breakpoints, returning whether each can be hit.
+    pub fn set_breakpoints(&mut self, breakpoints: Vec<Breakpoint>) -> Vec<bool> {
+        let sections = &self.simulator.sections;
+        let verified = breakpoints
+            .iter()
+            .map(|breakpoint| match breakpoint {
+                Breakpoint::Line(line) => sections
+                    .iter()
+                    .any(|section| section.steps.iter().any(|step| step.span.line == *line)),
+                Breakpoint::Section(name) => sections.iter().any(|section| &section.name == name),
+            })
+            .collect();
+        self.breakpoints = breakpoints;
+        verified
+    }
+
+    pub fn resume(&mut self) -> DebugEvent {
+        self.run(StepMode::Continue)
+    }
+
+    pub fn step_in(&mut self) -> DebugEvent {
+        self.run(StepMode::In)
+    }
+
+    pub fn step_over(&mut self) -> DebugEvent {
+        self.run(StepMode::Over)
+    }
+
+    pub fn step_out(&mut self) -> DebugEvent {
+        self.run(StepMode::Out)
+    }
+
+    /// The call stack, innermost section first. Empty once the simulation
+    /// has ended.
+    pub fn stack_trace(&self) -> Vec<StackFrame> {
+        let sections = &self.simulator.sections;
+        self.simulator
+            .stack
+            .iter()
+            .rev()
+            .map(|frame| {
+                let section = &sections[frame.section];
+                StackFrame {
+                    section: section.name.clone(),
+                    line: section
+                        .steps
+                        .get(frame.next)
+                        .map_or(0, |step| step.span.line),
+                }
+            })
+            .collect()
+    }
+
+    /// The locals of the frame `frame` places from the innermost, in the
+    /// order the section declares them, or `None` if there is no such frame.
+    pub fn locals(&self, frame: usize) -> Option<Vec<DebugVariable>> {
+        let stack = &self.simulator.stack;
+        let frame = stack.get(stack.len().checked_sub(frame + 1)?)?;
+        let section = &self.simulator.sections[frame.section];
+        Some(
+            section
+                .locals
+                .iter()
+                .zip(&frame.locals)
+                .map(|(name, value)| DebugVariable {
+                    name: name.clone(),
+                    value: *value,
+                })
+                .collect(),
+        )
+    }
+
+    /// The globals, sorted by name.
+    pub fn globals(&self) -> Vec<DebugVariable> {
+        let mut globals: Vec<_> = self
+            .simulator
+            .globals
+            .iter()
+            .map(|(name, value)| DebugVariable {
+                name: name.clone(),
+                value: *value,
+            })
+            .collect();
+        globals.sort_by(|a, b| a.name.cmp(&b.name));
+        globals
+    }
+
+    /// The car now, with the line of the instruction that ran last.
+    pub fn state(&self) -> Keyframe {
+        self.simulator.keyframe()
+    }
+
+    /// Why the simulation ended, or `None` while it can still run.
+    pub fn stop(&self) -> Option<StopReason> {
+        self.simulator.stop
+    }
+
+    /// The event to report right after starting: paused on entry to `main`.
+    pub fn entry(&mut self) -> DebugEvent {
+        self.paused(PauseReason::Entry)
+    }
+
+    /// Carries out a request.
+    pub fn handle(&mut self, request: DebugRequest) -> DebugEvent {
+        match request {
+            DebugRequest::SetBreakpoints { breakpoints } => {
+                let verified = self.set_breakpoints(breakpoints.clone());
+                DebugEvent::Breakpoints {
+                    breakpoints,
+                    verified,
+                }
+            }
+            DebugRequest::Continue => self.resume(),
+            DebugRequest::StepIn => self.step_in(),
+            DebugRequest::StepOver => self.step_over(),
+            DebugRequest::StepOut => self.step_out(),
+            DebugRequest::StackTrace => DebugEvent::StackTrace {
+                frames: self.stack_trace(),
+            },
+            DebugRequest::Variables { frame } => match self.locals(frame) {
+                Some(locals) => DebugEvent::Variables {
+                    locals,
+                    globals: self.globals(),
+                },
+                None => DebugEvent::Error {
+                    message: format!(
+                        "No frame {}, the call stack has {} frames",
+                        frame,
+                        self.simulator.stack.len()
+                    ),
+                    diagnostics: Vec::new(),
+                },
+            },
+        }
+    }
+
+    fn run(&mut self, mode: StepMode) -> DebugEvent {
+        let start = self.location();
+        let mut previous = start;
+        while self.simulator.stop.is_none() {
+            self.simulator.step();
+            let location = self.location();
+            let Some(here) = location.filter(|_| location != previous) else {
+                previous = location;
+                continue;
+            };
+            // `main` restarting counts as a call, like any other
+            let called = previous.is_none_or(|previous| here.depth > previous.depth);
+            previous = location;
+
+            if self.breakpoint_at(here, called) {
+                return self.paused(PauseReason::Breakpoint);
+            }
+            let depth = start.map_or(0, |start| start.depth);
+            let pause = match mode {
+                StepMode::Continue => false,
+                StepMode::In => true,
+                StepMode::Over => here.depth <= depth,
+                StepMode::Out => here.depth < depth || called && here.depth == 1,
+            };
+            if pause {
+                return self.paused(PauseReason::Step);
+            }
+        }
+        self.paused(PauseReason::Step)
+    }
+
+    fn breakpoint_at(&self, here: Location, called: bool) -> bool {
+        let section = &self.simulator.sections[here.section].name;
+        self.breakpoints.iter().any(|breakpoint| match breakpoint {
+            Breakpoint::Line(line) => *line == here.line,
+            Breakpoint::Section(name) => called && name == section,
+        })
+    }
+
+    /// The event for the current position, or the simulation's end.
+    fn paused(&mut self, reason: PauseReason) -> DebugEvent {
+        if self.simulator.stop.is_some() {
+            return DebugEvent::Terminated {
+                simulation: self.simulator.finish(),
+            };
+        }
+        match self.stack_trace().into_iter().next() {
+            Some(frame) => DebugEvent::Stopped {
+                reason,
+                frame,
+                state: self.state(),
+            },
+            // Between runs of `main` with `loop_main`; step to its restart
+            None => self.run(StepMode::In),
+        }
+    }
+
+    fn location(&self) -> Option<Location> {
+        let frame = self.simulator.stack.last()?;
+        let section = &self.simulator.sections[frame.section];
+        Some(Location {
+            depth: self.simulator.stack.len(),
+            section: frame.section,
+            line: section
+                .steps
+                .get(frame.next)
+                .map_or(0, |step| step.span.line),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    const PROGRAM: &str = "\
+var total = 0
+
+double:
+    var twice = total
+    add total, twice
+    ret
+
+main:
+    add total, 1
+    jal double
+    jal double
+    mov forward, 1
+";
+
+    fn debugger() -> Debugger {
+        let program = Parser::new(PROGRAM.to_string()).parse().unwrap();
+        Debugger::new(&program, &SimulationOptions::default())
+    }
+
+    fn line(event: &DebugEvent) -> usize {
+        match event {
+            DebugEvent::Stopped { frame, .. } => frame.line,
+            other => panic!("expected a pause, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_step_in_and_out() {
+        let mut debugger = debugger();
+        assert_eq!(line(&debugger.entry()), 9);
+        assert_eq!(line(&debugger.step_in()), 10);
+        assert_eq!(line(&debugger.step_in()), 4);
+        assert_eq!(
+            debugger.stack_trace(),
+            vec![
+                StackFrame {
+                    section: "double".to_string(),
+                    line: 4
+                },
+                StackFrame {
+                    section: "main".to_string(),
+                    line: 11
+                },
+            ]
+        );
+
+        assert_eq!(line(&debugger.step_over()), 5);
+        assert_eq!(
+            debugger.locals(0),
+            Some(vec![DebugVariable {
+                name: "twice".to_string(),
+                value: 1
+            }])
+        );
+        assert_eq!(debugger.locals(1), Some(Vec::new()));
+        assert_eq!(debugger.locals(2), None);
+
+        assert_eq!(line(&debugger.step_out()), 11);
+        assert_eq!(
+            debugger.globals(),
+            vec![DebugVariable {
+                name: "total".to_string(),
+                value: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_breakpoints_and_step_over() {
+        let mut debugger = debugger();
+        debugger.entry();
+        assert_eq!(
+            debugger.set_breakpoints(vec![
+                Breakpoint::Section("double".to_string()),
+                Breakpoint::Line(2),
+            ]),
+            vec![true, false]
+        );
+
+        // Hit on each call
+        assert_eq!(line(&debugger.resume()), 4);
+        assert_eq!(line(&debugger.step_out()), 11);
+        assert_eq!(line(&debugger.resume()), 4);
+
+        debugger.set_breakpoints(Vec::new());
+        assert_eq!(line(&debugger.step_out()), 12);
+        let before = debugger.state().time_ms;
+        // The implicit return at the end of `main` is on its label's line
+        assert_eq!(line(&debugger.step_over()), 8);
+        assert_eq!(debugger.state().time_ms, before + 2000);
+        match debugger.step_over() {
+            DebugEvent::Terminated { simulation } => {
+                assert_eq!(simulation.stop, StopReason::Finished);
+                assert_eq!(simulation.duration_ms, before + 2000);
+            }
+            other => panic!("expected the end, got {:?}", other),
+        }
+        assert!(matches!(debugger.step_in(), DebugEvent::Terminated { .. }));
+    }
+}
@@ -1,18 +1,287 @@
+use crate::span::Span;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A value read by an instruction: either a literal or a variable.
+///
+/// Serialized untagged, so literals stay plain JSON numbers and variables
+/// are plain strings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Operand {
+    Literal(i32),
+    Variable(String),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Literal(n) => write!(f, "{}", n),
+            Operand::Variable(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// What a `mov` instruction drives. Serialized in lowercase, matching the
+/// names used in the source (`"type": "forward"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MoveKind {
+    /// Drive forward for a time, or a distance in `cm`
+    Forward,
+    /// Drive backward for a time, or a distance in `cm`
+    Backward,
+    /// Steer: 0 is straight, 1 is left and 2 is right
+    Direction,
+    /// Stop and wait for a time
+    Wait,
+    /// Set the motor power, as a percentage from 0 to 100 of the robot
+    /// profile's duty cycle
+    Speed,
+    /// Steer left and drive forward for a time, or until the car has
+    /// turned an angle in `deg`, then straighten up
+    Left,
+    /// Like `Left`, turning right
+    Right,
+}
+
+impl MoveKind {
+    pub const ALL: [MoveKind; 7] = [
+        MoveKind::Forward,
+        MoveKind::Backward,
+        MoveKind::Direction,
+        MoveKind::Wait,
+        MoveKind::Speed,
+        MoveKind::Left,
+        MoveKind::Right,
+    ];
+
+    /// The name used for this kind in the source and the JSON IR.
+    pub fn name(self) -> &'static str {
+        match self {
+            MoveKind::Forward => "forward",
+            MoveKind::Backward => "backward",
+            MoveKind::Direction => "direction",
+            MoveKind::Wait => "wait",
+            MoveKind::Speed => "speed",
+            MoveKind::Left => "left",
+            MoveKind::Right => "right",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        MoveKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// The units this kind of move accepts besides plain seconds.
+    pub fn units(self) -> &'static [Unit] {
+        match self {
+            MoveKind::Forward | MoveKind::Backward => &[Unit::Ms, Unit::Cm],
+            MoveKind::Left | MoveKind::Right => &[Unit::Ms, Unit::Deg],
+            MoveKind::Wait => &[Unit::Ms],
+            MoveKind::Direction | MoveKind::Speed => &[],
+        }
+    }
+}
+
+impl fmt::Display for MoveKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A unit suffix on a `mov` amount, like `30cm`. Amounts without a unit
+/// are in seconds, except for `direction` and `speed`. Fractional seconds
+/// and `s` suffixes are converted to milliseconds by the lexer, so the IR
+/// only ever holds whole numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    /// Milliseconds
+    Ms,
+    /// Centimeters, converted to a drive time with the robot profile's
+    /// calibrated speed
+    Cm,
+    /// Degrees of heading change, converted to a turn time with the robot
+    /// profile's calibrated turn rate
+    Deg,
+}
+
+impl Unit {
+    pub const ALL: [Unit; 3] = [Unit::Ms, Unit::Cm, Unit::Deg];
+
+    /// The suffix used for this unit in the source and the JSON IR.
+    pub fn name(self) -> &'static str {
+        match self {
+            Unit::Ms => "ms",
+            Unit::Cm => "cm",
+            Unit::Deg => "deg",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Unit::ALL.into_iter().find(|unit| unit.name() == name)
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A `var name = value` declaration.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Variable {
+    pub name: String,
+    pub value: Operand,
+    #[serde(skip)]
+    pub span: Span,
+}
+
+/// The comparison made by a conditional branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Condition {
+    /// `beq`
+    Equal,
+    /// `bne`
+    NotEqual,
+    /// `blt`
+    Less,
+    /// `bge`
+    GreaterOrEqual,
+}
+
+/// A single instruction.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub enum Command {
-    Move { r#type: String, amount: i32 },
-    Jump { label: String },
+    /// `mov <type>, <amount>`
+    Move {
+        r#type: MoveKind,
+        amount: Operand,
+        /// Unit of a literal amount; whole seconds when absent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        unit: Option<Unit>,
+        #[serde(skip)]
+        span: Span,
+    },
+    /// `jal <label>`: calls another section
+    Jump {
+        label: String,
+        #[serde(skip)]
+        span: Span,
+    },
+    /// Declares a variable local to the section.
+    Var {
+        name: String,
+        value: Operand,
+        #[serde(skip)]
+        span: Span,
+    },
+    /// `variable += value`, wrapping around on 32-bit two's complement
+    /// overflow.
+    Add {
+        variable: String,
+        value: Operand,
+        #[serde(skip)]
+        span: Span,
+    },
+    /// `variable -= value`, wrapping around on 32-bit two's complement
+    /// overflow.
+    Sub {
+        variable: String,
+        value: Operand,
+        #[serde(skip)]
+        span: Span,
+    },
+    /// A `.name:` jump target local to the section.
+    Label {
+        name: String,
+        #[serde(skip)]
+        span: Span,
+    },
+    /// `j .label`
+    Goto {
+        label: String,
+        #[serde(skip)]
+        span: Span,
+    },
+    /// Jumps to `label` when `left <condition> right` holds.
+    Branch {
+        condition: Condition,
+        left: Operand,
+        right: Operand,
+        label: String,
+        #[serde(skip)]
+        span: Span,
+    },
+    /// Runs `body` `count` times. The count is read once, when the loop
+    /// starts; zero or negative counts skip the body.
+    Repeat {
+        count: Operand,
+        body: Vec<Command>,
+        #[serde(skip)]
+        span: Span,
+    },
+    /// Leaves the current section early. In `main` this restarts the loop.
+    Return {
+        #[serde(skip)]
+        span: Span,
+    },
+}
+
+impl Command {
+    /// The source region this command was parsed from.
+    pub fn span(&self) -> Span {
+        match self {
+            Command::Move { span, .. }
+            | Command::Jump { span, .. }
+            | Command::Var { span, .. }
+            | Command::Add { span, .. }
+            | Command::Sub { span, .. }
+            | Command::Label { span, .. }
+            | Command::Goto { span, .. }
+            | Command::Branch { span, .. }
+            | Command::Repeat { span, .. }
+            | Command::Return { span } => *span,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A named block of commands, compiled to a function.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Section {
     pub name: String,
     pub commands: Vec<Command>,
+    #[serde(skip)]
+    pub span: Span,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Version of the JSON IR format written by this compiler.
+///
+/// Bump it whenever a change to the IR would make older readers
+/// misinterpret new output.
+///
+/// Versions:
+/// - 1: the original format
+/// - 2: units on `mov` amounts, which version 1 readers would silently read
+///   as seconds
+pub const IR_VERSION: u32 = 2;
+
+/// A whole program, as serialized to the JSON IR.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Program {
+    /// IR format version; IR saved before versioning was introduced has no
+    /// version and is read as version 1
+    #[serde(default = "first_version")]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub globals: Vec<Variable>,
     pub sections: Vec<Section>,
 }
+
+fn first_version() -> u32 {
+    1
+}
@@ -1,16 +1,53 @@
+use crate::diagnostic::{Diagnostic, Stage};
+use crate::ir::Unit;
+use crate::span::Span;
+
 #[derive(Debug, PartialEq, Clone)]
-pub enum Token {
+pub enum TokenKind {
     Colon,
     Identifier(String),
     Number(i32),
+    /// A number with a unit suffix, like `30cm`
+    Quantity(i32, Unit),
     Comma,
+    Equals,
     Eof,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// A `#` or `;` line comment, kept so tools can reproduce the original source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
+}
+
+/// A lexing failure; the lexer has already skipped past the offending text.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LexError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl From<LexError> for Diagnostic {
+    fn from(error: LexError) -> Self {
+        Diagnostic::error(Stage::Lexer, error.message, error.span)
+    }
+}
+
+#[derive(Debug)]
 pub struct Lexer {
     chars: Vec<char>,
     position: usize,
+    offset: usize,
+    line: usize,
+    column: usize,
+    comments: Vec<Comment>,
 }
 
 impl Lexer {
@@ -18,65 +55,281 @@ impl Lexer {
         Lexer {
             chars: input.chars().collect(),
             position: 0,
+            offset: 0,
+            line: 1,
+            column: 1,
+            comments: Vec::new(),
         }
     }
 
-    pub fn next_token(&mut self) -> Token {
-        self.skip_whitespace();
+    pub fn next_token(&mut self) -> Result<Token, LexError> {
+        self.skip_trivia();
+
+        let start = (self.offset, self.line, self.column);
 
         if self.position >= self.chars.len() {
-            return Token::Eof;
+            return Ok(self.token_from(TokenKind::Eof, start));
         }
 
         let current_char = self.chars[self.position];
 
-        match current_char {
+        let kind = match current_char {
             ':' => {
-                self.position += 1;
-                Token::Colon
+                self.bump();
+                TokenKind::Colon
             }
             ',' => {
-                self.position += 1;
-                Token::Comma
+                self.bump();
+                TokenKind::Comma
+            }
+            '=' => {
+                self.bump();
+                TokenKind::Equals
             }
-            '0'..='9' => {
-                let start = self.position;
-                while self.position < self.chars.len() && self.chars[self.position].is_digit(10) {
-                    self.position += 1;
+            '0'..='9' => self.lex_number(start)?,
+            '-' if self.peek(1).is_some_and(|c| c.is_ascii_digit()) => self.lex_number(start)?,
+            // A leading '.' marks a label local to the current section
+            c if is_identifier_char(c) || (c == '.' && self.next_is_identifier_char()) => {
+                let first = self.position;
+                self.bump();
+                while self.position < self.chars.len()
+                    && is_identifier_char(self.chars[self.position])
+                {
+                    self.bump();
                 }
-                let num_str: String = self.chars[start..self.position].iter().collect();
-                Token::Number(num_str.parse().unwrap())
+                let ident: String = self.chars[first..self.position].iter().collect();
+                TokenKind::Identifier(ident)
             }
-            _ if !current_char.is_whitespace() => {
-                let start = self.position;
-                while self.position < self.chars.len() {
-                    let c = self.chars[self.position];
-                    if c.is_whitespace() || c == ':' || c == ',' {
-                        break;
-                    }
-                    self.position += 1;
+            _ => {
+                self.bump();
+                return Err(
+                    self.error_from(format!("Unexpected character '{}'", current_char), start)
+                );
+            }
+        };
+
+        Ok(self.token_from(kind, start))
+    }
+
+    /// Lexes a number: an optional `-`, then either `0x`/`0b` digits or
+    /// decimal digits with an optional fraction and unit suffix. Digits can
+    /// be grouped with `_`. Times are normalized to whole milliseconds, so
+    /// `0.5`, `0.5s` and `500ms` all become `Quantity(500, Unit::Ms)`; plain
+    /// integers stay `Number`s.
+    fn lex_number(&mut self, start: (usize, usize, usize)) -> Result<TokenKind, LexError> {
+        let first = self.position;
+        let negative = self.peek(0) == Some('-');
+        if negative {
+            self.bump();
+        }
+
+        let radix = match (self.peek(0), self.peek(1)) {
+            (Some('0'), Some('x' | 'X')) => Some(16),
+            (Some('0'), Some('b' | 'B')) => Some(2),
+            _ => None,
+        };
+        if let Some(radix) = radix {
+            self.bump();
+            self.bump();
+            let body = self.take_while(is_identifier_char);
+            let text: String = self.chars[first..self.position].iter().collect();
+            return self.radix_literal(negative, radix, &body, &text, start);
+        }
+
+        let digits = self.take_while(|c| c.is_ascii_digit() || c == '_');
+        let fraction =
+            if self.peek(0) == Some('.') && self.peek(1).is_some_and(|c| c.is_ascii_digit()) {
+                self.bump();
+                Some(self.take_while(|c| c.is_ascii_digit()))
+            } else {
+                None
+            };
+        let number: String = self.chars[first..self.position].iter().collect();
+        // A unit suffix has to follow the digits directly
+        let suffix = self.take_while(is_identifier_char);
+        let text: String = self.chars[first..self.position].iter().collect();
+
+        let sign = if negative { "-" } else { "" };
+        let digits = digits.replace('_', "");
+        let integer = |lexer: &Self| {
+            format!("{}{}", sign, digits).parse::<i32>().map_err(|_| {
+                lexer.error_from(
+                    format!("Integer literal '{}' is out of range", number),
+                    start,
+                )
+            })
+        };
+
+        match (suffix.as_str(), &fraction) {
+            ("", None) => Ok(TokenKind::Number(integer(self)?)),
+            ("" | "s", _) => {
+                let fraction = fraction.as_deref().unwrap_or("");
+                if fraction.len() > 3 {
+                    return Err(self.error_from(
+                        format!("'{}' is more precise than a millisecond", text),
+                        start,
+                    ));
+                }
+                let millis = digits.parse::<i64>().ok().and_then(|seconds| {
+                    let fraction = format!("{:0<3}", fraction).parse::<i64>().ok()?;
+                    let millis = seconds.checked_mul(1000)? + fraction;
+                    i32::try_from(if negative { -millis } else { millis }).ok()
+                });
+                match millis {
+                    Some(millis) => Ok(TokenKind::Quantity(millis, Unit::Ms)),
+                    None => Err(self.error_from(format!("Time '{}' is out of range", text), start)),
                 }
-                let ident: String = self.chars[start..self.position].iter().collect();
-                Token::Identifier(ident)
             }
-            _ => panic!("Unexpected character: {}", current_char),
+            (_, Some(_)) if Unit::from_name(&suffix).is_some() => Err(self.error_from(
+                format!(
+                    "Only times in seconds can have a fraction, found '{}'",
+                    text
+                ),
+                start,
+            )),
+            (_, _) => match Unit::from_name(&suffix) {
+                Some(unit) => Ok(TokenKind::Quantity(integer(self)?, unit)),
+                None => Err(self.error_from(
+                    format!(
+                        "Unknown unit '{}' in '{}', expected one of: s, {}",
+                        suffix,
+                        text,
+                        Unit::ALL.map(|unit| unit.name()).join(", ")
+                    ),
+                    start,
+                )),
+            },
+        }
+    }
+
+    /// Checks and converts the digits of a `0x` or `0b` literal, which
+    /// cannot have a fraction or unit.
+    fn radix_literal(
+        &self,
+        negative: bool,
+        radix: u32,
+        body: &str,
+        text: &str,
+        start: (usize, usize, usize),
+    ) -> Result<TokenKind, LexError> {
+        let name = if radix == 16 { "hexadecimal" } else { "binary" };
+        if let Some(c) = body.chars().find(|&c| c != '_' && !c.is_digit(radix)) {
+            return Err(self.error_from(
+                format!("Invalid digit '{}' in {} literal '{}'", c, name, text),
+                start,
+            ));
+        }
+        let digits = body.replace('_', "");
+        if digits.is_empty() {
+            return Err(
+                self.error_from(format!("Expected {} digits after '{}'", name, text), start)
+            );
+        }
+
+        i64::from_str_radix(&digits, radix)
+            .ok()
+            .and_then(|n| i32::try_from(if negative { -n } else { n }).ok())
+            .map(TokenKind::Number)
+            .ok_or_else(|| {
+                self.error_from(format!("Integer literal '{}' is out of range", text), start)
+            })
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let first = self.position;
+        while self.position < self.chars.len() && predicate(self.chars[self.position]) {
+            self.bump();
+        }
+        self.chars[first..self.position].iter().collect()
+    }
+
+    fn peek(&self, ahead: usize) -> Option<char> {
+        self.chars.get(self.position + ahead).copied()
+    }
+
+    /// Lexes the next token without consuming it. Rewinds to where it
+    /// started rather than lexing a copy, which would copy the whole input
+    /// and every comment so far on each peek.
+    pub fn peek_next_token(&mut self) -> Result<Token, LexError> {
+        let (position, offset, line, column) = (self.position, self.offset, self.line, self.column);
+        let comments = self.comments.len();
+        let token = self.next_token();
+        self.position = position;
+        self.offset = offset;
+        self.line = line;
+        self.column = column;
+        self.comments.truncate(comments);
+        token
+    }
+
+    fn token_from(&self, kind: TokenKind, (offset, line, column): (usize, usize, usize)) -> Token {
+        Token {
+            kind,
+            span: Span::new(offset, self.offset, line, column),
+        }
+    }
+
+    fn next_is_identifier_char(&self) -> bool {
+        self.chars
+            .get(self.position + 1)
+            .is_some_and(|&c| is_identifier_char(c))
+    }
+
+    fn error_from(
+        &self,
+        message: String,
+        (offset, line, column): (usize, usize, usize),
+    ) -> LexError {
+        LexError {
+            message,
+            span: Span::new(offset, self.offset, line, column),
         }
     }
 
-    pub fn peek_next_token(&self) -> Token {
-        let mut clone = self.clone();
-        clone.next_token()
+    fn bump(&mut self) {
+        let c = self.chars[self.position];
+        self.position += 1;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
     }
 
-    fn skip_whitespace(&mut self) {
+    /// Comments skipped so far, in source order.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    fn skip_trivia(&mut self) {
         while self.position < self.chars.len() {
             let c = self.chars[self.position];
-            if !c.is_whitespace() {
+            if c == '#' || c == ';' {
+                self.skip_comment();
+            } else if c.is_whitespace() {
+                self.bump();
+            } else {
                 break;
             }
-            self.position += 1;
         }
     }
+
+    fn skip_comment(&mut self) {
+        let start = (self.offset, self.line, self.column);
+        let first = self.position;
+        while self.position < self.chars.len() && self.chars[self.position] != '\n' {
+            self.bump();
+        }
+        let text: String = self.chars[first..self.position].iter().collect();
+        let span = Span::new(start.0, self.offset, start.1, start.2);
+        self.comments.push(Comment { text, span });
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
 }
 
 #[cfg(test)]
@@ -88,16 +341,22 @@ mod tests {
         let input = "circle: mov direction, 1".to_string();
         let mut lexer = Lexer::new(input);
 
-        assert_eq!(lexer.next_token(), Token::Identifier("circle".to_string()));
-        assert_eq!(lexer.next_token(), Token::Colon);
-        assert_eq!(lexer.next_token(), Token::Identifier("mov".to_string()));
         assert_eq!(
-            lexer.next_token(),
-            Token::Identifier("direction".to_string())
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("circle".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Colon);
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("mov".to_string())
         );
-        assert_eq!(lexer.next_token(), Token::Comma);
-        assert_eq!(lexer.next_token(), Token::Number(1));
-        assert_eq!(lexer.next_token(), Token::Eof);
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("direction".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Comma);
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Number(1));
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Eof);
     }
 
     #[test]
@@ -111,20 +370,35 @@ mod tests {
         .to_string();
         let mut lexer = Lexer::new(input);
 
-        assert_eq!(lexer.next_token(), Token::Identifier("circle".to_string()));
-        assert_eq!(lexer.next_token(), Token::Colon);
-        assert_eq!(lexer.next_token(), Token::Identifier("mov".to_string()));
         assert_eq!(
-            lexer.next_token(),
-            Token::Identifier("direction".to_string())
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("circle".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Colon);
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("mov".to_string())
         );
-        assert_eq!(lexer.next_token(), Token::Comma);
-        assert_eq!(lexer.next_token(), Token::Number(1));
-        assert_eq!(lexer.next_token(), Token::Identifier("main".to_string()));
-        assert_eq!(lexer.next_token(), Token::Colon);
-        assert_eq!(lexer.next_token(), Token::Identifier("jal".to_string()));
-        assert_eq!(lexer.next_token(), Token::Identifier("circle".to_string()));
-        assert_eq!(lexer.next_token(), Token::Eof);
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("direction".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Comma);
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Number(1));
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("main".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Colon);
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("jal".to_string())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("circle".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Eof);
     }
 
     #[test]
@@ -132,15 +406,229 @@ mod tests {
         let input = "  circle  :  mov  direction  ,  1  ".to_string();
         let mut lexer = Lexer::new(input);
 
-        assert_eq!(lexer.next_token(), Token::Identifier("circle".to_string()));
-        assert_eq!(lexer.next_token(), Token::Colon);
-        assert_eq!(lexer.next_token(), Token::Identifier("mov".to_string()));
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("circle".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Colon);
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("mov".to_string())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("direction".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Comma);
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Number(1));
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Eof);
+    }
+
+    #[test]
+    fn test_lexer_spans() {
+        let input = "main:\n    jal circle".to_string();
+        let mut lexer = Lexer::new(input);
+
+        assert_eq!(lexer.next_token().unwrap().span, Span::new(0, 4, 1, 1));
+        assert_eq!(lexer.next_token().unwrap().span, Span::new(4, 5, 1, 5));
+        assert_eq!(lexer.next_token().unwrap().span, Span::new(10, 13, 2, 5));
+        assert_eq!(lexer.next_token().unwrap().span, Span::new(14, 20, 2, 9));
+        assert_eq!(lexer.next_token().unwrap().span, Span::new(20, 20, 2, 15));
+    }
+
+    #[test]
+    fn test_lexer_errors() {
+        let input = "mov @ 99999999999, 1".to_string();
+        let mut lexer = Lexer::new(input);
+
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("mov".to_string())
+        );
+        assert_eq!(
+            lexer.next_token(),
+            Err(LexError {
+                message: "Unexpected character '@'".to_string(),
+                span: Span::new(4, 5, 1, 5),
+            })
+        );
         assert_eq!(
             lexer.next_token(),
-            Token::Identifier("direction".to_string())
+            Err(LexError {
+                message: "Integer literal '99999999999' is out of range".to_string(),
+                span: Span::new(6, 17, 1, 7),
+            })
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Comma);
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Number(1));
+    }
+
+    #[test]
+    fn test_lexer_comments() {
+        let input = "# header\nmain: ; entry point\n    jal circle # call".to_string();
+        let mut lexer = Lexer::new(input);
+
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("main".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Colon);
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("jal".to_string())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("circle".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Eof);
+
+        assert_eq!(
+            lexer.comments(),
+            &[
+                Comment {
+                    text: "# header".to_string(),
+                    span: Span::new(0, 8, 1, 1),
+                },
+                Comment {
+                    text: "; entry point".to_string(),
+                    span: Span::new(15, 28, 2, 7),
+                },
+                Comment {
+                    text: "# call".to_string(),
+                    span: Span::new(44, 50, 3, 16),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_peek() {
+        let input = "main # entry\n: jal".to_string();
+        let mut lexer = Lexer::new(input);
+        lexer.next_token().unwrap();
+
+        // Peeking leaves the position and the comments alone
+        let peeked = lexer.peek_next_token().unwrap();
+        assert_eq!(peeked.kind, TokenKind::Colon);
+        assert!(lexer.comments().is_empty());
+        assert_eq!(lexer.next_token().unwrap(), peeked);
+        assert_eq!(lexer.comments().len(), 1);
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("jal".to_string())
+        );
+    }
+
+    #[test]
+    fn test_lexer_units() {
+        let mut lexer = Lexer::new("30cm 90deg 5 12km".to_string());
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Quantity(30, Unit::Cm)
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Quantity(90, Unit::Deg)
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Number(5));
+
+        let error = lexer.next_token().unwrap_err();
+        assert_eq!(
+            error.message,
+            "Unknown unit 'km' in '12km', expected one of: s, ms, cm, deg"
+        );
+        assert_eq!(error.span, Span::new(13, 17, 1, 14));
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Eof);
+    }
+
+    #[test]
+    fn test_lexer_times() {
+        let mut lexer = Lexer::new("0.5 2s 1.25s 250ms 3".to_string());
+        for millis in [500, 2000, 1250, 250] {
+            assert_eq!(
+                lexer.next_token().unwrap().kind,
+                TokenKind::Quantity(millis, Unit::Ms)
+            );
+        }
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Number(3));
+
+        let errors = [
+            ("0.0005", "'0.0005' is more precise than a millisecond"),
+            (
+                "0.5ms",
+                "Only times in seconds can have a fraction, found '0.5ms'",
+            ),
+            (
+                "1.5cm",
+                "Only times in seconds can have a fraction, found '1.5cm'",
+            ),
+            ("3000000s", "Time '3000000s' is out of range"),
+        ];
+        for (input, message) in errors {
+            let mut lexer = Lexer::new(input.to_string());
+            assert_eq!(lexer.next_token().unwrap_err().message, message);
+            assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Eof);
+        }
+    }
+
+    #[test]
+    fn test_lexer_integer_literals() {
+        let mut lexer =
+            Lexer::new("-5 0x1F -0x10 0b1010 1_000 0xFF_FF -2147483648 -0.5 -30cm".to_string());
+        for n in [-5, 31, -16, 10, 1000, 65535, i32::MIN] {
+            assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Number(n));
+        }
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Quantity(-500, Unit::Ms)
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Quantity(-30, Unit::Cm)
+        );
+
+        let errors = [
+            ("0x1G", "Invalid digit 'G' in hexadecimal literal '0x1G'"),
+            ("0b102", "Invalid digit '2' in binary literal '0b102'"),
+            ("0x", "Expected hexadecimal digits after '0x'"),
+            (
+                "0x1_0000_0000",
+                "Integer literal '0x1_0000_0000' is out of range",
+            ),
+            (
+                "2_147_483_648",
+                "Integer literal '2_147_483_648' is out of range",
+            ),
+            ("- 5", "Unexpected character '-'"),
+        ];
+        for (input, message) in errors {
+            let mut lexer = Lexer::new(input.to_string());
+            assert_eq!(lexer.next_token().unwrap_err().message, message);
+        }
+    }
+
+    #[test]
+    fn test_lexer_local_labels() {
+        let input = ".loop: j .loop .".to_string();
+        let mut lexer = Lexer::new(input);
+
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier(".loop".to_string())
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Colon);
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier("j".to_string())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap().kind,
+            TokenKind::Identifier(".loop".to_string())
+        );
+        assert_eq!(
+            lexer.next_token().unwrap_err().message,
+            "Unexpected character '.'"
         );
-        assert_eq!(lexer.next_token(), Token::Comma);
-        assert_eq!(lexer.next_token(), Token::Number(1));
-        assert_eq!(lexer.next_token(), Token::Eof);
     }
 }
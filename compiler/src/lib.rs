@@ -1,23 +1,78 @@
+mod arena;
+mod callgraph;
 mod codegen;
+mod debugger;
+mod diagnostic;
 mod ir;
 mod lexer;
+mod lower;
 mod parser;
+mod profile;
+mod render;
+mod semantic;
+mod settings;
+mod simulator;
+mod span;
 
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum CompilerError {
-    #[error("Lexer error: {0}")]
-    LexerError(String),
-    #[error("Parser error: {0}")]
-    ParserError(String),
+    #[error("{}", display_diagnostics(.0))]
+    Diagnostics(Vec<Diagnostic>),
     #[error("Codegen error: {0}")]
     CodegenError(String),
+    #[error("Unknown target '{0}', expected one of: {}", TARGETS.join(", "))]
+    UnknownTarget(String),
 }
 
-pub use codegen::generate_arduino_code;
-pub use ir::{Command, Program, Section};
+impl CompilerError {
+    /// Every diagnostic attached to the error, in source order.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            CompilerError::Diagnostics(diagnostics) => diagnostics,
+            CompilerError::CodegenError(_) | CompilerError::UnknownTarget(_) => &[],
+        }
+    }
+
+    /// The source region of the first diagnostic, if there is one.
+    pub fn span(&self) -> Option<Span> {
+        self.diagnostics().first().map(|diagnostic| diagnostic.span)
+    }
+}
+
+fn display_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(Diagnostic::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub use arena::{Arena, Goal, Rect, Wall};
+pub use callgraph::{analyze_calls, CallGraph, MAX_CALL_DEPTH};
+pub use codegen::{
+    backend, generate, generate_arduino_code, ArduinoBackend, Backend, CodegenOptions,
+    StateMachineBackend, TARGETS,
+};
+pub use debugger::{
+    Breakpoint, DebugEvent, DebugRequest, DebugVariable, Debugger, PauseReason, StackFrame,
+};
+pub use diagnostic::{Diagnostic, Severity, Stage};
+pub use ir::{Command, Condition, MoveKind, Operand, Program, Section, Unit, Variable, IR_VERSION};
+pub use lexer::Comment;
 pub use parser::Parser;
+pub use profile::{CalibrationProfile, MotorProfile, RobotProfile, TimingProfile};
+#[cfg(feature = "png")]
+pub use render::render_png;
+pub use render::{render_svg, RenderOptions};
+pub use semantic::{analyze, check_entry_point, SymbolTable};
+pub use settings::SettingsError;
+pub use simulator::{
+    Collision, Drive, GoalReached, Keyframe, Pose, Simulation, SimulationOptions, Steering,
+    StopReason,
+};
+pub use span::Span;
 
 /// Compiles the input assembly code to IR (JSON format)
 ///
@@ -29,8 +84,7 @@ pub use parser::Parser;
 ///
 /// Returns a JSON string representing the IR, or an error if compilation fails
 pub fn compile(input: String) -> Result<String, CompilerError> {
-    let mut parser = Parser::new(input);
-    let program = parser.parse().map_err(CompilerError::ParserError)?;
+    let program = parse_and_check(input, false)?;
     serde_json::to_string_pretty(&program).map_err(|e| CompilerError::CodegenError(e.to_string()))
 }
 
@@ -44,7 +98,269 @@ pub fn compile(input: String) -> Result<String, CompilerError> {
 ///
 /// Returns the generated Arduino C++ code, or an error if compilation fails
 pub fn compile_to_arduino(input: String) -> Result<String, CompilerError> {
+    compile_to("arduino", input)
+}
+
+/// Compiles the input assembly code with the backend for `target`
+///
+/// # Arguments
+///
+/// * `target` - The name of the backend, one of `TARGETS`
+/// * `input` - The assembly code to compile
+///
+/// # Returns
+///
+/// Returns the generated code, or an error if the target is unknown or
+/// compilation fails
+pub fn compile_to(target: &str, input: String) -> Result<String, CompilerError> {
+    compile_to_with(target, input, &CodegenOptions::default())
+}
+
+/// Compiles the input assembly code with the backend for `target`,
+/// configured with `options`, e.g. for a different robot profile
+pub fn compile_to_with(
+    target: &str,
+    input: String,
+    options: &CodegenOptions,
+) -> Result<String, CompilerError> {
+    let backend = find_backend(target, options)?;
+    let program = parse_and_check(input, true)?;
+    generate(backend.as_ref(), &program).map_err(CompilerError::CodegenError)
+}
+
+/// Compiles previously generated JSON IR to Arduino C++ code
+///
+/// # Arguments
+///
+/// * `ir_json` - The IR, as produced by `compile`
+///
+/// # Returns
+///
+/// Returns the generated Arduino C++ code, or an error if the IR cannot be
+/// read, has an unsupported version or fails the same checks as source code.
+/// IR carries no source spans, so semantic diagnostics have empty spans.
+pub fn compile_ir_to_arduino(ir_json: String) -> Result<String, CompilerError> {
+    compile_ir_to("arduino", ir_json)
+}
+
+/// Compiles previously generated JSON IR with the backend for `target`
+///
+/// # Arguments
+///
+/// * `target` - The name of the backend, one of `TARGETS`
+/// * `ir_json` - The IR, as produced by `compile`
+///
+/// # Returns
+///
+/// Returns the generated code, or an error if the target is unknown or the
+/// IR is rejected as by `compile_ir_to_arduino`
+pub fn compile_ir_to(target: &str, ir_json: String) -> Result<String, CompilerError> {
+    compile_ir_to_with(target, ir_json, &CodegenOptions::default())
+}
+
+/// Compiles previously generated JSON IR with the backend for `target`,
+/// configured with `options`
+pub fn compile_ir_to_with(
+    target: &str,
+    ir_json: String,
+    options: &CodegenOptions,
+) -> Result<String, CompilerError> {
+    let backend = find_backend(target, options)?;
+    let program = read_ir(&ir_json)?;
+    let diagnostics = check_program(&program, true);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(CompilerError::Diagnostics(diagnostics));
+    }
+    generate(backend.as_ref(), &program).map_err(CompilerError::CodegenError)
+}
+
+fn find_backend(target: &str, options: &CodegenOptions) -> Result<Box<dyn Backend>, CompilerError> {
+    backend(target, options).ok_or_else(|| CompilerError::UnknownTarget(target.to_string()))
+}
+
+/// Runs the input assembly code on a simulated robot
+///
+/// # Arguments
+///
+/// * `input` - The assembly code to run
+/// * `options` - The robot profile, start pose and limits of the run
+///
+/// # Returns
+///
+/// Returns the car's trace until `main` returns or a limit is reached, or
+/// an error if the code does not compile
+pub fn simulate(input: String, options: &SimulationOptions) -> Result<Simulation, CompilerError> {
+    let program = parse_and_check(input, true)?;
+    Ok(simulator::simulate_program(&program, options))
+}
+
+/// Starts a debugging session for the input assembly code on a simulated
+/// robot
+///
+/// # Arguments
+///
+/// * `input` - The assembly code to debug
+/// * `options` - The robot profile, start pose and limits of the run
+///
+/// # Returns
+///
+/// Returns a `Debugger` paused on entry to `main`, or an error if the code
+/// does not compile
+pub fn debug(input: String, options: &SimulationOptions) -> Result<Debugger, CompilerError> {
+    let program = parse_and_check(input, true)?;
+    Ok(Debugger::new(&program, options))
+}
+
+/// Returns the JSON Schema describing the IR produced by `compile`
+pub fn ir_schema() -> String {
+    let schema = schemars::schema_for!(Program);
+    serde_json::to_string_pretty(&schema).expect("schema serializes to JSON")
+}
+
+/// Deserializes JSON IR and checks that this compiler understands its
+/// version and that every name in it is an identifier.
+fn read_ir(ir_json: &str) -> Result<Program, CompilerError> {
+    let program: Program = serde_json::from_str(ir_json).map_err(|e| {
+        let span = Span::new(0, 0, e.line(), e.column());
+        CompilerError::Diagnostics(vec![Diagnostic::error(Stage::Ir, e.to_string(), span)])
+    })?;
+
+    if program.version == 0 || program.version > IR_VERSION {
+        return Err(CompilerError::Diagnostics(vec![Diagnostic::error(
+            Stage::Ir,
+            format!(
+                "Unsupported IR version {}, this compiler reads versions 1 to {}",
+                program.version, IR_VERSION
+            ),
+            Span::default(),
+        )]));
+    }
+
+    let diagnostics = check_ir_names(&program);
+    if !diagnostics.is_empty() {
+        return Err(CompilerError::Diagnostics(diagnostics));
+    }
+
+    Ok(program)
+}
+
+/// Rejects names in the IR that the parser could never have produced.
+/// Backends paste names straight into the generated code, so anything but
+/// a plain identifier could smuggle code into it.
+fn check_ir_names(program: &Program) -> Vec<Diagnostic> {
+    fn is_identifier(name: &str) -> bool {
+        let mut chars = name.chars();
+        chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    fn check_name(kind: &str, name: &str, diagnostics: &mut Vec<Diagnostic>) {
+        if !is_identifier(name) {
+            diagnostics.push(Diagnostic::error(
+                Stage::Ir,
+                format!("Invalid {} name {:?}", kind, name),
+                Span::default(),
+            ));
+        }
+    }
+
+    fn check_operand(operand: &Operand, diagnostics: &mut Vec<Diagnostic>) {
+        if let Operand::Variable(name) = operand {
+            check_name("variable", name, diagnostics);
+        }
+    }
+
+    fn check_commands(commands: &[Command], diagnostics: &mut Vec<Diagnostic>) {
+        for command in commands {
+            match command {
+                Command::Move { amount, .. } => check_operand(amount, diagnostics),
+                Command::Jump { label, .. } => check_name("section", label, diagnostics),
+                Command::Var { name, value, .. } => {
+                    check_name("variable", name, diagnostics);
+                    check_operand(value, diagnostics);
+                }
+                Command::Add {
+                    variable, value, ..
+                }
+                | Command::Sub {
+                    variable, value, ..
+                } => {
+                    check_name("variable", variable, diagnostics);
+                    check_operand(value, diagnostics);
+                }
+                Command::Label { name: label, .. } | Command::Goto { label, .. } => {
+                    check_name("label", label, diagnostics)
+                }
+                Command::Branch {
+                    left, right, label, ..
+                } => {
+                    check_operand(left, diagnostics);
+                    check_operand(right, diagnostics);
+                    check_name("label", label, diagnostics);
+                }
+                Command::Repeat { count, body, .. } => {
+                    check_operand(count, diagnostics);
+                    check_commands(body, diagnostics);
+                }
+                Command::Return { .. } => {}
+            }
+        }
+    }
+
+    let mut diagnostics = Vec::new();
+    for global in &program.globals {
+        check_name("variable", &global.name, &mut diagnostics);
+        check_operand(&global.value, &mut diagnostics);
+    }
+    for section in &program.sections {
+        check_name("section", &section.name, &mut diagnostics);
+        check_commands(&section.commands, &mut diagnostics);
+    }
+    diagnostics
+}
+
+/// Checks the input assembly code without generating anything
+///
+/// # Arguments
+///
+/// * `input` - The assembly code to check
+///
+/// # Returns
+///
+/// Returns every error and warning the compiler would report for the input,
+/// in source order. Warnings do not stop `compile` or `compile_to_arduino`,
+/// so this is the only way to see them when compilation succeeds.
+pub fn check(input: String) -> Vec<Diagnostic> {
+    let mut parser = Parser::new(input);
+    match parser.parse() {
+        Ok(program) => check_program(&program, true),
+        Err(diagnostics) => diagnostics,
+    }
+}
+
+/// Runs every analysis pass over a parsed program. The IR can describe a
+/// program without a `main` section, but code generation sets
+/// `require_main`.
+fn check_program(program: &Program, require_main: bool) -> Vec<Diagnostic> {
+    let mut diagnostics = analyze(program);
+    if require_main {
+        diagnostics.extend(check_entry_point(program));
+    }
+    diagnostics.extend(analyze_calls(program));
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+    diagnostics
+}
+
+/// Parses the input and runs the analysis passes, failing if any of them
+/// reports an error.
+fn parse_and_check(input: String, require_main: bool) -> Result<Program, CompilerError> {
     let mut parser = Parser::new(input);
-    let program = parser.parse().map_err(CompilerError::ParserError)?;
-    generate_arduino_code(&program).map_err(CompilerError::CodegenError)
+    let program = parser.parse().map_err(CompilerError::Diagnostics)?;
+    let diagnostics = check_program(&program, require_main);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(CompilerError::Diagnostics(diagnostics));
+    }
+    Ok(program)
 }
@@ -0,0 +1,353 @@
+use crate::ir::{Command, Condition, MoveKind, Operand, Program, Section, Unit};
+use crate::span::Span;
+use std::collections::HashMap;
+
+/// One step of a lowered section.
+///
+/// Lowering flattens the nested IR into a list of instructions per section
+/// with explicit jumps, which is what a state machine or an interpreter
+/// wants to execute: a position in the program is just a section and an
+/// index into its steps.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Move {
+        kind: MoveKind,
+        amount: Operand,
+        unit: Option<Unit>,
+    },
+    /// `var name = value`, also used to reset a local when its `var` runs
+    Set {
+        variable: String,
+        value: Operand,
+    },
+    Add {
+        variable: String,
+        value: Operand,
+    },
+    Sub {
+        variable: String,
+        value: Operand,
+    },
+    /// Continues at step `target`
+    Jump {
+        target: usize,
+    },
+    /// Continues at step `target` when `left <condition> right` holds
+    Branch {
+        condition: Condition,
+        left: Operand,
+        right: Operand,
+        target: usize,
+    },
+    /// Runs another section, then continues with the next step
+    Call {
+        section: String,
+    },
+    /// Leaves the section. Every lowered section ends with one
+    Return,
+    /// Loads a `repeat` count into hidden loop counter `counter`
+    StartLoop {
+        counter: usize,
+        count: Operand,
+    },
+    /// The top of a `repeat` loop: continues at `exit` once the counter is
+    /// used up, otherwise decrements it and runs the body
+    Loop {
+        counter: usize,
+        exit: usize,
+    },
+}
+
+/// An instruction and the source it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub instruction: Instruction,
+    pub span: Span,
+}
+
+/// A section flattened into steps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoweredSection {
+    pub name: String,
+    pub steps: Vec<Step>,
+    /// Local variables declared anywhere in the section, in order
+    pub locals: Vec<String>,
+    /// Number of hidden `repeat` counters, one per nesting level
+    pub counters: usize,
+}
+
+/// Lowers every section of a program, in order.
+pub(crate) fn lower(program: &Program) -> Result<Vec<LoweredSection>, String> {
+    program.sections.iter().map(lower_section).collect()
+}
+
+/// Lowers a single section. The semantic pass checks that its labels are
+/// defined; a jump to an undefined one is an error here.
+pub(crate) fn lower_section(section: &Section) -> Result<LoweredSection, String> {
+    let mut lowerer = Lowerer {
+        steps: Vec::new(),
+        labels: HashMap::new(),
+        pending: Vec::new(),
+        locals: Vec::new(),
+        counters: 0,
+    };
+    lowerer.lower_commands(&section.commands, 0);
+    lowerer.push(Instruction::Return, section.span);
+
+    // Labels are only known once the whole section has been seen
+    for (index, label) in lowerer.pending {
+        let Some(&target) = lowerer.labels.get(label.as_str()) else {
+            return Err(format!(
+                "Undefined label '.{}' in section '{}'",
+                label, section.name
+            ));
+        };
+        match &mut lowerer.steps[index].instruction {
+            Instruction::Jump { target: slot } | Instruction::Branch { target: slot, .. } => {
+                *slot = target;
+            }
+            _ => unreachable!("only jumps and branches refer to labels"),
+        }
+    }
+
+    Ok(LoweredSection {
+        name: section.name.clone(),
+        steps: lowerer.steps,
+        locals: lowerer.locals,
+        counters: lowerer.counters,
+    })
+}
+
+struct Lowerer {
+    steps: Vec<Step>,
+    /// The step each label points at
+    labels: HashMap<String, usize>,
+    /// Jumps and branches whose label still has to be resolved
+    pending: Vec<(usize, String)>,
+    locals: Vec<String>,
+    counters: usize,
+}
+
+impl Lowerer {
+    fn lower_commands(&mut self, commands: &[Command], depth: usize) {
+        for command in commands {
+            let span = command.span();
+            match command {
+                Command::Move {
+                    r#type,
+                    amount,
+                    unit,
+                    ..
+                } => self.push(
+                    Instruction::Move {
+                        kind: *r#type,
+                        amount: amount.clone(),
+                        unit: *unit,
+                    },
+                    span,
+                ),
+                Command::Jump { label, .. } => self.push(
+                    Instruction::Call {
+                        section: label.clone(),
+                    },
+                    span,
+                ),
+                Command::Var { name, value, .. } => {
+                    if !self.locals.contains(name) {
+                        self.locals.push(name.clone());
+                    }
+                    self.push(
+                        Instruction::Set {
+                            variable: name.clone(),
+                            value: value.clone(),
+                        },
+                        span,
+                    );
+                }
+                Command::Add {
+                    variable, value, ..
+                } => self.push(
+                    Instruction::Add {
+                        variable: variable.clone(),
+                        value: value.clone(),
+                    },
+                    span,
+                ),
+                Command::Sub {
+                    variable, value, ..
+                } => self.push(
+                    Instruction::Sub {
+                        variable: variable.clone(),
+                        value: value.clone(),
+                    },
+                    span,
+                ),
+                Command::Label { name, .. } => {
+                    self.labels.insert(name.clone(), self.steps.len());
+                }
+                Command::Goto { label, .. } => {
+                    self.pending.push((self.steps.len(), label.clone()));
+                    self.push(Instruction::Jump { target: 0 }, span);
+                }
+                Command::Branch {
+                    condition,
+                    left,
+                    right,
+                    label,
+                    ..
+                } => {
+                    self.pending.push((self.steps.len(), label.clone()));
+                    self.push(
+                        Instruction::Branch {
+                            condition: *condition,
+                            left: left.clone(),
+                            right: right.clone(),
+                            target: 0,
+                        },
+                        span,
+                    );
+                }
+                Command::Repeat { count, body, .. } => {
+                    self.counters = self.counters.max(depth + 1);
+                    self.push(
+                        Instruction::StartLoop {
+                            counter: depth,
+                            count: count.clone(),
+                        },
+                        span,
+                    );
+                    let top = self.steps.len();
+                    self.push(
+                        Instruction::Loop {
+                            counter: depth,
+                            exit: 0,
+                        },
+                        span,
+                    );
+                    self.lower_commands(body, depth + 1);
+                    self.push(Instruction::Jump { target: top }, span);
+                    let exit = self.steps.len();
+                    if let Instruction::Loop { exit: slot, .. } = &mut self.steps[top].instruction {
+                        *slot = exit;
+                    }
+                }
+                Command::Return { .. } => self.push(Instruction::Return, span),
+            }
+        }
+    }
+
+    fn push(&mut self, instruction: Instruction, span: Span) {
+        self.steps.push(Step { instruction, span });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn lower_source(input: &str) -> Vec<LoweredSection> {
+        let program = Parser::new(input.to_string()).parse().unwrap();
+        lower(&program).unwrap()
+    }
+
+    fn instructions(section: &LoweredSection) -> Vec<Instruction> {
+        section
+            .steps
+            .iter()
+            .map(|step| step.instruction.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_lower_labels_and_calls() {
+        let sections = lower_source(
+            r#"
+            turn:
+                mov direction, 1
+            main:
+                var i = 0
+            .top:
+                jal turn
+                add i, 1
+                blt i, 3, .top
+                j .end
+                ret
+            .end:
+            "#,
+        );
+
+        assert_eq!(sections[1].locals, vec!["i".to_string()]);
+        assert_eq!(
+            instructions(&sections[1]),
+            vec![
+                Instruction::Set {
+                    variable: "i".to_string(),
+                    value: Operand::Literal(0),
+                },
+                Instruction::Call {
+                    section: "turn".to_string(),
+                },
+                Instruction::Add {
+                    variable: "i".to_string(),
+                    value: Operand::Literal(1),
+                },
+                Instruction::Branch {
+                    condition: Condition::Less,
+                    left: Operand::Variable("i".to_string()),
+                    right: Operand::Literal(3),
+                    target: 1,
+                },
+                Instruction::Jump { target: 6 },
+                Instruction::Return,
+                Instruction::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lower_nested_repeat() {
+        let sections = lower_source(
+            r#"
+            main:
+                repeat 2:
+                    repeat 3:
+                        mov forward, 1
+                    end
+                end
+            "#,
+        );
+
+        assert_eq!(sections[0].counters, 2);
+        let forward = Instruction::Move {
+            kind: MoveKind::Forward,
+            amount: Operand::Literal(1),
+            unit: None,
+        };
+        assert_eq!(
+            instructions(&sections[0]),
+            vec![
+                Instruction::StartLoop {
+                    counter: 0,
+                    count: Operand::Literal(2),
+                },
+                Instruction::Loop {
+                    counter: 0,
+                    exit: 7,
+                },
+                Instruction::StartLoop {
+                    counter: 1,
+                    count: Operand::Literal(3),
+                },
+                Instruction::Loop {
+                    counter: 1,
+                    exit: 6,
+                },
+                forward,
+                Instruction::Jump { target: 3 },
+                Instruction::Jump { target: 1 },
+                Instruction::Return,
+            ]
+        );
+    }
+}
@@ -1,27 +1,176 @@
-use compiler::{compile, compile_to_arduino, CompilerError};
+use compiler::{
+    backend, compile, compile_to_with, render_svg, simulate, Arena, CodegenOptions, RenderOptions,
+    RobotProfile, Simulation, SimulationOptions, TARGETS,
+};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process;
 
-fn main() -> Result<(), CompilerError> {
-    let input = r#"
+const USAGE: &str =
+    "Usage: compiler [--target <name>] [--profile <robot.toml|robot.json>] [--non-blocking] [--render <path.svg|path.png>] [--arena <map.toml|map.json>] [--list-targets] [file]";
+
+const DEMO: &str = r#"
 circle:
     mov direction, 1
     mov forward, 4
     mov direction, 0
 
 main:
-    jal circle
-    jal circle
+    repeat 2:
+        jal circle
+    end
     mov forward, 10
     jal circle
-"#
-    .to_string();
+"#;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut target = None;
+    let mut path = None;
+    let mut render = None;
+    let mut arena = None;
+    let mut options = CodegenOptions::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--target" | "-t" => match args.next() {
+                Some(name) => target = Some(name),
+                None => usage("--target needs a target name"),
+            },
+            "--profile" | "-p" => match args.next() {
+                Some(profile) => options.profile = RobotProfile::load(profile)?,
+                None => usage("--profile needs a profile file"),
+            },
+            "--non-blocking" => options.non_blocking = true,
+            "--render" | "-r" => match args.next() {
+                Some(image) => render = Some(image),
+                None => usage("--render needs an output file"),
+            },
+            "--arena" | "-a" => match args.next() {
+                Some(map) => arena = Some(Arena::load(map)?),
+                None => usage("--arena needs a map file"),
+            },
+            "--list-targets" => {
+                for name in TARGETS {
+                    let backend = backend(name, &options).expect("listed target has a backend");
+                    println!("{} (.{})", name, backend.file_extension());
+                }
+                return Ok(());
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if arg.starts_with('-') => usage(&format!("Unknown option '{}'", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage("Only one input file can be compiled at a time"),
+        }
+    }
+
+    // The arena only affects the simulation, which only runs for an image
+    if arena.is_some() && render.is_none() {
+        usage("--arena needs --render");
+    }
+
+    // Draw the path the program drives instead of compiling it
+    if let Some(image) = render {
+        let input = match &path {
+            Some(path) => fs::read_to_string(path)?,
+            None => DEMO.to_string(),
+        };
+        let simulation = simulate(
+            input,
+            &SimulationOptions {
+                profile: options.profile,
+                arena: arena.clone(),
+                ..SimulationOptions::default()
+            },
+        )?;
+        let render_options = RenderOptions {
+            arena,
+            ..RenderOptions::default()
+        };
+        write_image(Path::new(&image), &simulation, &render_options)?;
+
+        // What happened in the arena, for grading
+        for goal in &simulation.goals {
+            println!(
+                "Reached goal '{}' at {:.1} s",
+                goal.name,
+                seconds(goal.time_ms)
+            );
+        }
+        for collision in &simulation.collisions {
+            println!(
+                "Hit {} at {:.1} s",
+                collision.obstacle,
+                seconds(collision.time_ms)
+            );
+        }
+        return Ok(());
+    }
+
+    // With a file, print only the requested target's output
+    if let Some(path) = path {
+        let input = fs::read_to_string(&path)?;
+        let target = target.as_deref().unwrap_or("arduino");
+        print!("{}", compile_to_with(target, input, &options)?);
+        return Ok(());
+    }
+
+    let input = DEMO.to_string();
 
     println!("IR Output:");
     let ir_output = compile(input.clone())?;
     println!("{}", ir_output);
 
-    println!("\nArduino Output:");
-    let arduino_output = compile_to_arduino(input)?;
-    println!("{}", arduino_output);
+    let target = target.as_deref().unwrap_or("arduino");
+    println!("\nOutput for target {}:", target);
+    let output = compile_to_with(target, input, &options)?;
+    println!("{}", output);
+
+    Ok(())
+}
 
+/// Renders the simulated path in the format named by the file extension.
+fn write_image(
+    path: &Path,
+    simulation: &Simulation,
+    options: &RenderOptions,
+) -> Result<(), Box<dyn Error>> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("svg") => fs::write(path, render_svg(simulation, options))?,
+        #[cfg(feature = "png")]
+        Some("png") => fs::write(path, compiler::render_png(simulation, options)?)?,
+        #[cfg(not(feature = "png"))]
+        Some("png") => {
+            return Err("PNG output needs the compiler built with `--features png`".into())
+        }
+        _ => {
+            return Err(format!(
+                "Cannot render '{}', expected a .svg or .png file",
+                path.display()
+            )
+            .into())
+        }
+    }
     Ok(())
 }
+
+fn seconds(time_ms: u64) -> f64 {
+    time_ms as f64 / 1000.0
+}
+
+fn usage(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
@@ -1,122 +1,519 @@
-use crate::ir::{Command, Program, Section};
-use crate::lexer::{Lexer, Token};
+use crate::diagnostic::{Diagnostic, Stage};
+use crate::ir::{
+    Command, Condition, MoveKind, Operand, Program, Section, Unit, Variable, IR_VERSION,
+};
+use crate::lexer::{Comment, Lexer, Token, TokenKind};
+use crate::span::Span;
 
 pub struct Parser {
     lexer: Lexer,
     current_token: Token,
+    previous_span: Span,
+    diagnostics: Vec<Diagnostic>,
+    /// How many `repeat` blocks enclose the current command
+    repeat_depth: usize,
+    /// The line the command being parsed starts on. Its operands must be on
+    /// the same line
+    command_line: usize,
 }
 
 impl Parser {
     pub fn new(input: String) -> Self {
-        let mut lexer = Lexer::new(input);
-        let current_token = lexer.next_token();
         Parser {
-            lexer,
-            current_token,
+            lexer: Lexer::new(input),
+            current_token: Token {
+                kind: TokenKind::Eof,
+                span: Span::default(),
+            },
+            previous_span: Span::default(),
+            diagnostics: Vec::new(),
+            repeat_depth: 0,
+            command_line: 0,
         }
     }
 
-    pub fn parse(&mut self) -> Result<Program, String> {
+    /// Parses the whole input, recovering from errors so that every problem
+    /// in the program is reported at once.
+    pub fn parse(&mut self) -> Result<Program, Vec<Diagnostic>> {
+        // Prime the first token
+        self.advance();
+
+        let mut globals = Vec::new();
         let mut sections = Vec::new();
 
-        while self.current_token != Token::Eof {
-            sections.push(self.parse_section()?);
+        // Variables declared before the first section are globals
+        while self.at_keyword("var") {
+            let start = self.current_token.span;
+            self.command_line = start.line;
+            match self.parse_variable(start) {
+                Ok(variable) => globals.push(variable),
+                Err(diagnostic) => {
+                    self.report(diagnostic, start);
+                    self.synchronize_command(start);
+                }
+            }
+        }
+
+        while self.current_token.kind != TokenKind::Eof {
+            match self.parse_section() {
+                Ok(section) => sections.push(section),
+                Err(diagnostic) => {
+                    self.report(diagnostic, self.current_token.span);
+                    self.synchronize_section();
+                }
+            }
+        }
+
+        if self.diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(std::mem::take(&mut self.diagnostics));
         }
 
-        Ok(Program { sections })
+        Ok(Program {
+            version: IR_VERSION,
+            globals,
+            sections,
+        })
     }
 
-    fn parse_section(&mut self) -> Result<Section, String> {
+    /// Comments encountered so far, in source order.
+    pub fn comments(&self) -> &[Comment] {
+        self.lexer.comments()
+    }
+
+    fn parse_section(&mut self) -> Result<Section, Diagnostic> {
+        let start = self.current_token.span;
+        self.command_line = start.line;
+
         // Get section name
-        let name = if let Token::Identifier(name) = self.current_token.clone() {
-            self.advance();
-            name
-        } else {
-            return Err("Expected section name".to_string());
+        let name = match self.current_token.kind.clone() {
+            TokenKind::Identifier(name) if name.starts_with('.') => {
+                return Err(self.error(&format!("Local label '{}' must be inside a section", name)))
+            }
+            TokenKind::Identifier(name) => {
+                self.advance();
+                name
+            }
+            _ => return Err(self.error("Expected section name")),
         };
 
         // Expect colon
-        if let Token::Colon = self.current_token {
+        if let TokenKind::Colon = self.current_token.kind {
             self.advance();
         } else {
-            return Err("Expected ':' after section name".to_string());
+            return Err(self.error("Expected ':' after section name"));
+        }
+
+        let mut span = start.to(self.previous_span);
+        let commands = self.parse_commands();
+        if let Some(last) = commands.last() {
+            span = span.to(last.span());
         }
 
+        Ok(Section {
+            name,
+            commands,
+            span,
+        })
+    }
+
+    /// Parses commands up to the next section label, the end of the input
+    /// or, inside a `repeat` block, the closing `end`.
+    fn parse_commands(&mut self) -> Vec<Command> {
         let mut commands = Vec::new();
-        while self.current_token != Token::Eof {
-            // Check if we've reached a new section
-            if let Token::Identifier(_) = self.current_token {
-                if self.peek_next_token() == Token::Colon {
-                    break;
+        while self.current_token.kind != TokenKind::Eof && !self.at_section_label() {
+            if self.repeat_depth > 0 && self.at_keyword("end") {
+                break;
+            }
+            let command_start = self.current_token.span;
+            match self.parse_command() {
+                Ok(command) => commands.push(command),
+                Err(diagnostic) => {
+                    self.report(diagnostic, command_start);
+                    self.synchronize_command(command_start);
                 }
             }
-            commands.push(self.parse_command()?);
         }
-
-        Ok(Section { name, commands })
+        commands
     }
 
-    fn parse_command(&mut self) -> Result<Command, String> {
-        // Check if it's a jump instruction
-        if let Token::Identifier(ref cmd) = self.current_token {
-            if cmd == "jal" {
+    fn parse_command(&mut self) -> Result<Command, Diagnostic> {
+        let start = self.current_token.span;
+        self.command_line = start.line;
+
+        let instruction = if let TokenKind::Identifier(name) = self.current_token.kind.clone() {
+            name
+        } else {
+            return Err(self.error("Expected instruction"));
+        };
+
+        if let Some(name) = instruction.strip_prefix('.') {
+            if self.repeat_depth > 0 {
+                return Err(self.error("Labels are not allowed inside a repeat block"));
+            }
+            let name = name.to_string();
+            self.advance();
+            if let TokenKind::Colon = self.current_token.kind {
                 self.advance();
-                // Get label name
-                let label = if let Token::Identifier(label) = self.current_token.clone() {
-                    self.advance();
-                    label
-                } else {
-                    return Err("Expected label after jal".to_string());
-                };
-                return Ok(Command::Jump { label });
+            } else {
+                return Err(self.error("Expected ':' after label"));
             }
+            return Ok(Command::Label {
+                name,
+                span: start.to(self.previous_span),
+            });
         }
 
-        // Otherwise it's a mov command
-        if let Token::Identifier(ref cmd) = self.current_token {
-            if cmd != "mov" {
-                return Err("Expected 'mov' command or 'jal'".to_string());
+        match instruction.as_str() {
+            "jal" => self.parse_jump(start),
+            "j" => {
+                self.advance();
+                let label = self.parse_local_label()?;
+                Ok(Command::Goto {
+                    label,
+                    span: start.to(self.previous_span),
+                })
             }
+            "beq" | "bne" | "blt" | "bge" => self.parse_branch(start, &instruction),
+            "ret" => {
+                self.advance();
+                Ok(Command::Return { span: start })
+            }
+            "mov" => self.parse_move(start),
+            "var" => {
+                let Variable { name, value, span } = self.parse_variable(start)?;
+                Ok(Command::Var { name, value, span })
+            }
+            "add" | "sub" => self.parse_arithmetic(start, &instruction),
+            "repeat" => self.parse_repeat(start),
+            "end" => Err(self.error("'end' without a matching 'repeat'")),
+            _ => Err(self.error(&format!("Unknown instruction '{}'", instruction))),
+        }
+    }
+
+    fn parse_jump(&mut self, start: Span) -> Result<Command, Diagnostic> {
+        self.advance();
+
+        // Get label name
+        let label = if let TokenKind::Identifier(label) = self.operand() {
             self.advance();
+            label
         } else {
-            return Err("Expected 'mov' command or 'jal'".to_string());
+            return Err(self.error("Expected label after jal"));
+        };
+
+        Ok(Command::Jump {
+            label,
+            span: start.to(self.previous_span),
+        })
+    }
+
+    fn parse_repeat(&mut self, start: Span) -> Result<Command, Diagnostic> {
+        self.advance();
+
+        // Get repeat count; a variable here is followed by ':' so it cannot
+        // go through parse_operand
+        let count = match self.operand() {
+            TokenKind::Number(n) => Operand::Literal(n),
+            TokenKind::Identifier(name) if !name.starts_with('.') => Operand::Variable(name),
+            _ => return Err(self.error("Expected repeat count")),
+        };
+        self.advance();
+
+        // Expect colon
+        if let TokenKind::Colon = self.operand() {
+            self.advance();
+        } else {
+            return Err(self.error("Expected ':' after repeat count"));
         }
 
-        // Get direction
-        let direction = if let Token::Identifier(dir) = self.current_token.clone() {
+        self.repeat_depth += 1;
+        let body = self.parse_commands();
+        self.repeat_depth -= 1;
+        // The closing `end` is a line of its own
+        self.command_line = self.current_token.span.line;
+
+        // Expect end
+        if self.at_keyword("end") {
             self.advance();
-            dir
         } else {
-            return Err("Expected direction".to_string());
+            return Err(self.error("Expected 'end' to close repeat"));
+        }
+
+        Ok(Command::Repeat {
+            count,
+            body,
+            span: start.to(self.previous_span),
+        })
+    }
+
+    fn parse_branch(&mut self, start: Span, instruction: &str) -> Result<Command, Diagnostic> {
+        self.advance();
+
+        let condition = match instruction {
+            "beq" => Condition::Equal,
+            "bne" => Condition::NotEqual,
+            "blt" => Condition::Less,
+            _ => Condition::GreaterOrEqual,
         };
 
+        let left = self.parse_operand()?;
+
         // Expect comma
-        if let Token::Comma = self.current_token {
+        if let TokenKind::Comma = self.operand() {
             self.advance();
         } else {
-            return Err("Expected ',' after direction".to_string());
+            return Err(self.error("Expected ',' after operand"));
         }
 
-        // Get amount
-        let amount = if let Token::Number(n) = self.current_token.clone() {
+        let right = self.parse_operand()?;
+
+        // Expect comma
+        if let TokenKind::Comma = self.operand() {
             self.advance();
-            n
         } else {
-            return Err("Expected number".to_string());
+            return Err(self.error("Expected ',' after operand"));
+        }
+
+        let label = self.parse_local_label()?;
+
+        Ok(Command::Branch {
+            condition,
+            left,
+            right,
+            label,
+            span: start.to(self.previous_span),
+        })
+    }
+
+    /// Parses a `.name` label reference, returning the name without the dot.
+    fn parse_local_label(&mut self) -> Result<String, Diagnostic> {
+        match self.operand() {
+            TokenKind::Identifier(name) if name.starts_with('.') => {
+                self.advance();
+                Ok(name[1..].to_string())
+            }
+            _ => Err(self.error("Expected local label like '.loop'")),
+        }
+    }
+
+    fn parse_move(&mut self, start: Span) -> Result<Command, Diagnostic> {
+        self.advance();
+
+        // Get direction
+        let direction = match &self.operand() {
+            TokenKind::Identifier(name) => match MoveKind::from_name(name) {
+                Some(kind) => kind,
+                None => {
+                    let expected: Vec<&str> = MoveKind::ALL.iter().map(|k| k.name()).collect();
+                    return Err(self.error(&format!(
+                        "Unknown movement '{}', expected one of: {}",
+                        name,
+                        expected.join(", ")
+                    )));
+                }
+            },
+            _ => return Err(self.error("Expected direction")),
+        };
+        self.advance();
+
+        // Expect comma
+        if let TokenKind::Comma = self.operand() {
+            self.advance();
+        } else {
+            return Err(self.error("Expected ',' after direction"));
+        }
+
+        // Only a literal amount can carry a unit
+        let (amount, unit) = match self.operand() {
+            TokenKind::Quantity(n, unit) => {
+                self.advance();
+                (Operand::Literal(n), Some(unit))
+            }
+            _ => (self.parse_operand()?, None),
         };
 
         Ok(Command::Move {
             r#type: direction,
             amount,
+            unit,
+            span: start.to(self.previous_span),
+        })
+    }
+
+    fn parse_variable(&mut self, start: Span) -> Result<Variable, Diagnostic> {
+        self.advance();
+
+        // Get variable name
+        let name = if let TokenKind::Identifier(name) = self.operand() {
+            self.advance();
+            name
+        } else {
+            return Err(self.error("Expected variable name after var"));
+        };
+
+        // Expect equals
+        if let TokenKind::Equals = self.operand() {
+            self.advance();
+        } else {
+            return Err(self.error("Expected '=' after variable name"));
+        }
+
+        let value = self.parse_operand()?;
+
+        Ok(Variable {
+            name,
+            value,
+            span: start.to(self.previous_span),
+        })
+    }
+
+    fn parse_arithmetic(&mut self, start: Span, instruction: &str) -> Result<Command, Diagnostic> {
+        self.advance();
+
+        // Get destination variable
+        let variable = match self.operand() {
+            TokenKind::Identifier(name) if !self.at_section_label() => {
+                self.advance();
+                name
+            }
+            _ => return Err(self.error(&format!("Expected variable name after {}", instruction))),
+        };
+
+        // Expect comma
+        if let TokenKind::Comma = self.operand() {
+            self.advance();
+        } else {
+            return Err(self.error("Expected ',' after variable name"));
+        }
+
+        let value = self.parse_operand()?;
+        let span = start.to(self.previous_span);
+
+        Ok(if instruction == "add" {
+            Command::Add {
+                variable,
+                value,
+                span,
+            }
+        } else {
+            Command::Sub {
+                variable,
+                value,
+                span,
+            }
         })
     }
 
+    /// Parses a number or a variable name.
+    fn parse_operand(&mut self) -> Result<Operand, Diagnostic> {
+        let operand = match self.operand() {
+            TokenKind::Number(n) => Operand::Literal(n),
+            TokenKind::Quantity(_, Unit::Ms) => {
+                return Err(
+                    self.error("Times like '0.5' or '500ms' are only allowed as a mov amount")
+                )
+            }
+            TokenKind::Quantity(_, unit) => {
+                return Err(self.error(&format!("Unit '{}' is only allowed on a mov amount", unit)))
+            }
+            TokenKind::Identifier(name) if !self.at_section_label() => Operand::Variable(name),
+            _ => return Err(self.error("Expected number or variable")),
+        };
+        self.advance();
+        Ok(operand)
+    }
+
+    /// The current token, or `Eof` once it is on a later line than the
+    /// command being parsed, so that a missing operand is reported on the
+    /// command's line instead of being taken from the next one.
+    fn operand(&self) -> TokenKind {
+        if self.current_token.span.line == self.command_line {
+            self.current_token.kind.clone()
+        } else {
+            TokenKind::Eof
+        }
+    }
+
+    /// Moves to the next token, recording any lexer error on the way. Text
+    /// that fails to lex becomes a placeholder number, so a bad amount is
+    /// still consumed as one instead of the parser taking the next line's
+    /// tokens in its place.
     fn advance(&mut self) {
-        self.current_token = self.lexer.next_token();
+        self.previous_span = self.current_token.span;
+        self.current_token = match self.lexer.next_token() {
+            Ok(token) => token,
+            Err(error) => {
+                let span = error.span;
+                self.diagnostics.push(error.into());
+                Token {
+                    kind: TokenKind::Number(0),
+                    span,
+                }
+            }
+        };
+    }
+
+    fn at_keyword(&mut self, keyword: &str) -> bool {
+        matches!(&self.current_token.kind, TokenKind::Identifier(name) if name == keyword)
+            && !self.at_section_label()
+    }
+
+    fn at_section_label(&mut self) -> bool {
+        matches!(&self.current_token.kind, TokenKind::Identifier(name) if !name.starts_with('.'))
+            && self.peek_is_colon()
+    }
+
+    fn peek_is_colon(&mut self) -> bool {
+        matches!(
+            self.lexer.peek_next_token(),
+            Ok(Token {
+                kind: TokenKind::Colon,
+                ..
+            })
+        )
+    }
+
+    /// Records a parser error, unless a lexer error inside the same construct
+    /// already explains it.
+    fn report(&mut self, diagnostic: Diagnostic, construct_start: Span) {
+        if let Some(last) = self.diagnostics.last() {
+            if last.stage == Stage::Lexer && last.span.start >= construct_start.start {
+                return;
+            }
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Skips the rest of a malformed command: everything up to the next line
+    /// or section label.
+    fn synchronize_command(&mut self, command_start: Span) {
+        while self.current_token.kind != TokenKind::Eof
+            && self.current_token.span.line == command_start.line
+            && !self.at_section_label()
+        {
+            self.advance();
+        }
     }
 
-    fn peek_next_token(&self) -> Token {
-        self.lexer.peek_next_token()
+    /// Skips to the next section label after a malformed section header.
+    fn synchronize_section(&mut self) {
+        self.advance();
+        while self.current_token.kind != TokenKind::Eof && !self.at_section_label() {
+            self.advance();
+        }
+    }
+
+    /// Builds a parser error pointing at the current token or, when that is
+    /// on a later line than the command, just past the command's last token.
+    fn error(&self, message: &str) -> Diagnostic {
+        let span = if self.current_token.span.line == self.command_line {
+            self.current_token.span
+        } else {
+            let end = self.previous_span;
+            let column = end.column + (end.end - end.start);
+            Span::new(end.end, end.end, end.line, column)
+        };
+        Diagnostic::error(Stage::Parser, message, span)
     }
 }
 
@@ -141,9 +538,9 @@ mod tests {
         assert_eq!(program.sections[0].name, "circle");
         assert_eq!(program.sections[0].commands.len(), 3);
 
-        if let Command::Move { r#type, amount } = &program.sections[0].commands[0] {
-            assert_eq!(r#type, "direction");
-            assert_eq!(*amount, 1);
+        if let Command::Move { r#type, amount, .. } = &program.sections[0].commands[0] {
+            assert_eq!(*r#type, MoveKind::Direction);
+            assert_eq!(*amount, Operand::Literal(1));
         } else {
             panic!("Expected Move command");
         }
@@ -165,10 +562,147 @@ mod tests {
         assert_eq!(program.sections[0].name, "main");
         assert_eq!(program.sections[0].commands.len(), 2);
 
-        if let Command::Jump { label } = &program.sections[0].commands[0] {
+        if let Command::Jump { label, .. } = &program.sections[0].commands[0] {
             assert_eq!(label, "circle");
         } else {
             panic!("Expected Jump command");
         }
     }
+
+    #[test]
+    fn test_parse_spans() {
+        let input = "main:\n    jal circle\n    mov forward, 10\n".to_string();
+
+        let mut parser = Parser::new(input);
+        let program = parser.parse().unwrap();
+
+        let section = &program.sections[0];
+        assert_eq!(section.span, Span::new(0, 40, 1, 1));
+        assert_eq!(section.commands[0].span(), Span::new(10, 20, 2, 5));
+        assert_eq!(section.commands[1].span(), Span::new(25, 40, 3, 5));
+    }
+
+    #[test]
+    fn test_parse_return() {
+        let input = r#"
+        circle:
+            mov forward, 1
+            ret
+            mov forward, 2
+        "#
+        .to_string();
+
+        let mut parser = Parser::new(input);
+        let program = parser.parse().unwrap();
+
+        assert_eq!(program.sections[0].commands.len(), 3);
+        assert!(matches!(
+            program.sections[0].commands[1],
+            Command::Return { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_variables() {
+        let input = r#"
+        var speed = 5
+        main:
+            var distance = speed
+            mov forward, distance
+        "#
+        .to_string();
+
+        let mut parser = Parser::new(input);
+        let program = parser.parse().unwrap();
+
+        assert_eq!(program.globals.len(), 1);
+        assert_eq!(program.globals[0].name, "speed");
+        assert_eq!(program.globals[0].value, Operand::Literal(5));
+
+        if let Command::Var { name, value, .. } = &program.sections[0].commands[0] {
+            assert_eq!(name, "distance");
+            assert_eq!(*value, Operand::Variable("speed".to_string()));
+        } else {
+            panic!("Expected Var command");
+        }
+
+        if let Command::Move { amount, .. } = &program.sections[0].commands[1] {
+            assert_eq!(*amount, Operand::Variable("distance".to_string()));
+        } else {
+            panic!("Expected Move command");
+        }
+    }
+
+    #[test]
+    fn test_parse_branches() {
+        let input = r#"
+        main:
+            var i = 0
+        .loop:
+            add i, 1
+            blt i, 4, .loop
+            beq i, 4, .done
+            j .loop
+        .done:
+        "#
+        .to_string();
+
+        let mut parser = Parser::new(input);
+        let program = parser.parse().unwrap();
+
+        assert_eq!(program.sections.len(), 1);
+        let commands = &program.sections[0].commands;
+        assert_eq!(commands.len(), 7);
+
+        assert!(matches!(&commands[1], Command::Label { name, .. } if name == "loop"));
+        if let Command::Branch {
+            condition,
+            left,
+            right,
+            label,
+            ..
+        } = &commands[3]
+        {
+            assert_eq!(*condition, Condition::Less);
+            assert_eq!(*left, Operand::Variable("i".to_string()));
+            assert_eq!(*right, Operand::Literal(4));
+            assert_eq!(label, "loop");
+        } else {
+            panic!("Expected Branch command");
+        }
+        assert!(matches!(&commands[5], Command::Goto { label, .. } if label == "loop"));
+        assert!(matches!(&commands[6], Command::Label { name, .. } if name == "done"));
+    }
+
+    #[test]
+    fn test_parse_repeat() {
+        let input = r#"
+        main:
+            repeat 4:
+                repeat sides:
+                    mov forward, 1
+                end
+                mov direction, 1
+            end
+            mov wait, 1
+        "#
+        .to_string();
+
+        let mut parser = Parser::new(input);
+        let program = parser.parse().unwrap();
+
+        let commands = &program.sections[0].commands;
+        assert_eq!(commands.len(), 2);
+        if let Command::Repeat { count, body, .. } = &commands[0] {
+            assert_eq!(*count, Operand::Literal(4));
+            assert_eq!(body.len(), 2);
+            assert!(matches!(
+                &body[0],
+                Command::Repeat { count: Operand::Variable(name), body, .. }
+                    if name == "sides" && body.len() == 1
+            ));
+        } else {
+            panic!("Expected Repeat command");
+        }
+    }
 }
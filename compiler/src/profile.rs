@@ -0,0 +1,290 @@
+use crate::ir::Unit;
+use crate::settings::{self, Settings, SettingsError};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// How one motor of an L298N-style driver is wired.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MotorProfile {
+    /// PWM pin connected to the driver's enable input
+    pub enable: u8,
+    /// First direction input pin (`in1` for the drive motor, `in3` for
+    /// steering)
+    pub input1: u8,
+    /// Second direction input pin (`in2` or `in4`)
+    pub input2: u8,
+    /// PWM duty cycle written to `enable`, 0 (off) to 255 (full power)
+    #[serde(default = "full_duty")]
+    pub duty: u8,
+    /// Swaps the two direction inputs, for motors wired the other way round
+    #[serde(default)]
+    pub inverted: bool,
+}
+
+impl MotorProfile {
+    /// The pins the generated code uses as its first and second direction
+    /// input, swapped when the motor is inverted.
+    pub fn input_pins(&self) -> (u8, u8) {
+        if self.inverted {
+            (self.input2, self.input1)
+        } else {
+            (self.input1, self.input2)
+        }
+    }
+}
+
+/// Delays the generated code waits for the car to settle, in milliseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingProfile {
+    /// Pause before and after each drive move, and between straightening
+    /// up and turning
+    pub settle_ms: u32,
+    /// Time the steering motor is given to reach full lock or straighten up
+    pub turn_ms: u32,
+}
+
+impl Default for TimingProfile {
+    fn default() -> Self {
+        TimingProfile {
+            settle_ms: 500,
+            turn_ms: 500,
+        }
+    }
+}
+
+/// How fast the car moves at full power, measured on the real robot. Used
+/// to turn `cm` and `deg` amounts into drive times.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibrationProfile {
+    /// Distance driven per second
+    pub cm_per_second: f64,
+    /// Heading change per second while driving at full steering lock
+    pub degrees_per_second: f64,
+}
+
+impl Default for CalibrationProfile {
+    fn default() -> Self {
+        // Rough figures for the original chassis; measure your own robot
+        CalibrationProfile {
+            cm_per_second: 20.0,
+            degrees_per_second: 45.0,
+        }
+    }
+}
+
+/// The hardware a program is compiled for: the drive motor, the steering
+/// motor, the timings between moves and the calibrated speeds.
+///
+/// The default profile is the original chassis the compiler was written
+/// for, so programs compile exactly as before unless a profile is given.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RobotProfile {
+    pub name: String,
+    /// Motor A, which drives the car forward and backward
+    pub drive: MotorProfile,
+    /// Motor B, which turns the front wheels
+    pub steering: MotorProfile,
+    pub timing: TimingProfile,
+    pub calibration: CalibrationProfile,
+}
+
+impl Default for RobotProfile {
+    fn default() -> Self {
+        RobotProfile {
+            name: "default".to_string(),
+            drive: MotorProfile {
+                enable: 9,
+                input1: 3,
+                input2: 4,
+                duty: 255,
+                inverted: false,
+            },
+            steering: MotorProfile {
+                enable: 10,
+                input1: 5,
+                input2: 6,
+                duty: 255,
+                inverted: false,
+            },
+            timing: TimingProfile::default(),
+            calibration: CalibrationProfile::default(),
+        }
+    }
+}
+
+impl RobotProfile {
+    pub fn from_toml(source: &str) -> Result<Self, SettingsError> {
+        settings::from_toml(source)
+    }
+
+    pub fn from_json(source: &str) -> Result<Self, SettingsError> {
+        settings::from_json(source)
+    }
+
+    /// Loads a profile, picking the format from the file extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SettingsError> {
+        settings::load(path.as_ref())
+    }
+
+    /// How long a `mov` with this amount drives for at full power, in
+    /// milliseconds. Amounts without a unit are seconds.
+    pub fn duration_ms(&self, amount: i32, unit: Option<Unit>) -> i64 {
+        let per_second = match unit {
+            None => return i64::from(amount) * 1000,
+            Some(Unit::Ms) => return i64::from(amount),
+            Some(Unit::Cm) => self.calibration.cm_per_second,
+            Some(Unit::Deg) => self.calibration.degrees_per_second,
+        };
+        (f64::from(amount) * 1000.0 / per_second).round() as i64
+    }
+
+    /// Checks that no pin is wired to two motor inputs and that the
+    /// calibrated speeds are positive.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let speeds = [
+            ("calibration.cm_per_second", self.calibration.cm_per_second),
+            (
+                "calibration.degrees_per_second",
+                self.calibration.degrees_per_second,
+            ),
+        ];
+        for (name, speed) in speeds {
+            if !(speed.is_finite() && speed > 0.0) {
+                return Err(Self::invalid(format!(
+                    "{} must be a positive number, found {}",
+                    name, speed
+                )));
+            }
+        }
+
+        let pins = [
+            ("drive.enable", self.drive.enable),
+            ("drive.input1", self.drive.input1),
+            ("drive.input2", self.drive.input2),
+            ("steering.enable", self.steering.enable),
+            ("steering.input1", self.steering.input1),
+            ("steering.input2", self.steering.input2),
+        ];
+        for (i, (name, pin)) in pins.iter().enumerate() {
+            if let Some((other, _)) = pins[..i].iter().find(|(_, other)| other == pin) {
+                return Err(Self::invalid(format!(
+                    "Pin {} is used by both {} and {}",
+                    pin, other, name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Settings for RobotProfile {
+    const WHAT: &'static str = "profile";
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        RobotProfile::validate(self)
+    }
+}
+
+fn full_duty() -> u8 {
+    255
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_toml_keeps_defaults() {
+        let profile = RobotProfile::from_toml(
+            r#"
+            name = "blue chassis"
+
+            [drive]
+            enable = 11
+            input1 = 7
+            input2 = 8
+            inverted = true
+
+            [timing]
+            turn_ms = 350
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(profile.name, "blue chassis");
+        assert_eq!(profile.drive.duty, 255);
+        assert_eq!(profile.drive.input_pins(), (8, 7));
+        assert_eq!(profile.steering, RobotProfile::default().steering);
+        assert_eq!(profile.timing.settle_ms, 500);
+        assert_eq!(profile.timing.turn_ms, 350);
+    }
+
+    #[test]
+    fn test_json_profile() {
+        let profile = RobotProfile::from_json(
+            r#"{ "steering": { "enable": 11, "input1": 12, "input2": 13, "duty": 180 } }"#,
+        )
+        .unwrap();
+        assert_eq!(profile.steering.duty, 180);
+        assert_eq!(profile.drive, RobotProfile::default().drive);
+    }
+
+    #[test]
+    fn test_duration_ms() {
+        let profile = RobotProfile::from_toml(
+            r#"
+            [calibration]
+            cm_per_second = 25.0
+            degrees_per_second = 60.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(profile.duration_ms(4, None), 4000);
+        assert_eq!(profile.duration_ms(250, Some(Unit::Ms)), 250);
+        assert_eq!(profile.duration_ms(30, Some(Unit::Cm)), 1200);
+        assert_eq!(profile.duration_ms(90, Some(Unit::Deg)), 1500);
+        assert_eq!(profile.duration_ms(10, Some(Unit::Deg)), 167);
+    }
+
+    #[test]
+    fn test_invalid_profiles() {
+        let error = RobotProfile::from_toml(
+            r#"
+            [steering]
+            enable = 9
+            input1 = 5
+            input2 = 6
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid profile: Pin 9 is used by both drive.enable and steering.enable"
+        );
+
+        assert!(matches!(
+            RobotProfile::from_toml("[timing]\nsettle = 100"),
+            Err(SettingsError::Toml { .. })
+        ));
+        assert!(matches!(
+            RobotProfile::from_json(
+                r#"{ "drive": { "enable": 9, "input1": 3, "input2": 4, "duty": 300 } }"#
+            ),
+            Err(SettingsError::Json { .. })
+        ));
+        assert_eq!(
+            RobotProfile::from_toml("[calibration]\ncm_per_second = 0.0")
+                .unwrap_err()
+                .to_string(),
+            "Invalid profile: calibration.cm_per_second must be a positive number, found 0"
+        );
+        assert!(matches!(
+            RobotProfile::load("robot.yaml"),
+            Err(SettingsError::UnknownFormat { .. })
+        ));
+    }
+}
@@ -0,0 +1,469 @@
+use crate::arena::{Arena, Rect};
+use crate::simulator::{Keyframe, Simulation, Steering};
+use std::fmt::Write;
+#[cfg(feature = "png")]
+use std::sync::{Arc, OnceLock};
+
+/// Colors given to sections in the order they first run.
+const PALETTE: &[&str] = &[
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f",
+    "#bcbd22", "#17becf",
+];
+
+/// How to draw a path.
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    /// Image size in pixels
+    pub width: u32,
+    pub height: u32,
+    /// The arena the simulation ran in, drawn under the path
+    pub arena: Option<Arena>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            width: 800,
+            height: 600,
+            arena: None,
+        }
+    }
+}
+
+/// Draws the path of a simulation as an SVG image.
+///
+/// The path is colored by the section that was running, with a legend, and
+/// a marker wherever the wheels turn to full lock. The start is a green dot
+/// and the end an arrow in the car's final heading. North is up: the y axis
+/// of the simulation points up the image. With an arena, its walls, boxes
+/// and goals are drawn too, and a cross marks each collision.
+pub fn render_svg(simulation: &Simulation, options: &RenderOptions) -> String {
+    let mut points: Vec<(f64, f64)> = simulation
+        .trace
+        .iter()
+        .map(|keyframe| (keyframe.pose.x, keyframe.pose.y))
+        .collect();
+    if let Some(arena) = &options.arena {
+        points.extend(arena_points(arena));
+    }
+    let view = View::fit(&points, options);
+    let sections = sections(&simulation.trace);
+    let color = |name: &str| {
+        let index = sections.iter().position(|section| section == name);
+        PALETTE[index.unwrap_or(0) % PALETTE.len()]
+    };
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#,
+        w = options.width,
+        h = options.height
+    );
+    svg.push_str("  <rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n");
+    if let Some(arena) = &options.arena {
+        draw_arena(&mut svg, arena, &view);
+    }
+
+    // One polyline per stretch of the trace spent in a single section,
+    // starting where the previous one ended so the path has no gaps
+    for (name, points) in runs(&simulation.trace) {
+        let points: Vec<String> = points
+            .iter()
+            .map(|keyframe| {
+                let (x, y) = view.pose(keyframe);
+                format!("{:.1},{:.1}", x, y)
+            })
+            .collect();
+        let _ = writeln!(
+            svg,
+            r#"  <polyline class="section" data-section="{}" points="{}" fill="none" stroke="{}" stroke-width="2" stroke-linejoin="round"/>"#,
+            escape(name),
+            points.join(" "),
+            color(name)
+        );
+    }
+
+    for (previous, keyframe) in simulation
+        .trace
+        .iter()
+        .zip(simulation.trace.get(1..).unwrap_or_default())
+    {
+        if keyframe.steering == previous.steering || keyframe.steering == Steering::Straight {
+            continue;
+        }
+        let (x, y) = view.pose(keyframe);
+        let side = if keyframe.steering == Steering::Left {
+            "left"
+        } else {
+            "right"
+        };
+        let _ = writeln!(
+            svg,
+            r#"  <circle class="turn" cx="{:.1}" cy="{:.1}" r="4" fill="white" stroke="{}" stroke-width="2"><title>{} turn, {} line {}</title></circle>"#,
+            x,
+            y,
+            color(&keyframe.section),
+            side,
+            escape(&keyframe.section),
+            keyframe.line
+        );
+    }
+
+    if let (Some(first), Some(last)) = (simulation.trace.first(), simulation.trace.last()) {
+        let (x, y) = view.pose(first);
+        let _ = writeln!(
+            svg,
+            r##"  <circle class="start" cx="{:.1}" cy="{:.1}" r="5" fill="#2ca02c"/>"##,
+            x, y
+        );
+
+        // The image's y axis points down, so headings turn the other way
+        let (x, y) = view.pose(last);
+        let heading = last.pose.heading.to_radians();
+        let corner = |angle: f64, length: f64| {
+            format!(
+                "{:.1},{:.1}",
+                x + length * (heading + angle).cos(),
+                y - length * (heading + angle).sin()
+            )
+        };
+        let _ = writeln!(
+            svg,
+            r##"  <polygon class="end" points="{} {} {}" fill="#d62728"/>"##,
+            corner(0.0, 10.0),
+            corner(2.5, 7.0),
+            corner(-2.5, 7.0)
+        );
+    }
+
+    for collision in &simulation.collisions {
+        let (x, y) = view.point(collision.x, collision.y);
+        let _ = writeln!(
+            svg,
+            r##"  <path class="collision" d="M{:.1},{:.1} l10,10 m0,-10 l-10,10" stroke="#d62728" stroke-width="3"><title>Hit {} at {:.1} s</title></path>"##,
+            x - 5.0,
+            y - 5.0,
+            escape(&collision.obstacle),
+            collision.time_ms as f64 / 1000.0
+        );
+    }
+
+    // Legend in the top left, scale bar in the bottom left
+    for (index, name) in sections.iter().enumerate() {
+        let y = 16 + index * 18;
+        let _ = writeln!(
+            svg,
+            r#"  <rect x="10" y="{}" width="12" height="12" fill="{}"/><text x="28" y="{}">{}</text>"#,
+            y,
+            color(name),
+            y + 10,
+            escape(name)
+        );
+    }
+    let (length, pixels) = view.scale_bar();
+    let bottom = f64::from(options.height) - 16.0;
+    let _ = writeln!(
+        svg,
+        r#"  <line x1="10" y1="{b:.1}" x2="{:.1}" y2="{b:.1}" stroke="black" stroke-width="2"/><text x="10" y="{:.1}">{} cm</text>"#,
+        10.0 + pixels,
+        bottom - 6.0,
+        length,
+        b = bottom
+    );
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Draws the path of a simulation as a PNG image, see `render_svg`.
+#[cfg(feature = "png")]
+pub fn render_png(simulation: &Simulation, options: &RenderOptions) -> Result<Vec<u8>, String> {
+    use resvg::{tiny_skia, usvg};
+
+    let svg_options = usvg::Options {
+        fontdb: system_fonts(),
+        ..usvg::Options::default()
+    };
+    let tree = usvg::Tree::from_str(&render_svg(simulation, options), &svg_options)
+        .map_err(|e| e.to_string())?;
+    let mut pixmap = tiny_skia::Pixmap::new(options.width, options.height)
+        .ok_or_else(|| format!("Cannot render a {}x{} image", options.width, options.height))?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    pixmap.encode_png().map_err(|e| e.to_string())
+}
+
+/// The installed fonts, loaded on the first render since scanning them is
+/// slow.
+#[cfg(feature = "png")]
+fn system_fonts() -> Arc<resvg::usvg::fontdb::Database> {
+    use resvg::usvg::fontdb;
+
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = fontdb::Database::new();
+            fonts.load_system_fonts();
+
+            // `sans-serif` means Arial unless told otherwise, which many
+            // servers lack; fall back to any installed font so the labels
+            // still show
+            let query = fontdb::Query {
+                families: &[fontdb::Family::SansSerif],
+                ..Default::default()
+            };
+            if fonts.query(&query).is_none() {
+                let fallback = fonts
+                    .faces()
+                    .find_map(|face| face.families.first())
+                    .map(|(family, _)| family.clone());
+                if let Some(family) = fallback {
+                    fonts.set_sans_serif_family(family);
+                }
+            }
+            Arc::new(fonts)
+        })
+        .clone()
+}
+
+/// Maps simulation coordinates (cm, y up) onto the image (pixels, y down).
+struct View {
+    min_x: f64,
+    max_y: f64,
+    scale: f64,
+    offset_x: f64,
+    offset_y: f64,
+}
+
+impl View {
+    /// Fits all the points into the image, leaving a margin and keeping the
+    /// aspect ratio. Paths shorter than a metre are drawn at a metre's scale.
+    fn fit(points: &[(f64, f64)], options: &RenderOptions) -> Self {
+        const MARGIN: f64 = 40.0;
+        const MIN_EXTENT_CM: f64 = 100.0;
+
+        let start = points.first().copied().unwrap_or_default();
+        let xs = points.iter().map(|&(x, _)| x);
+        let ys = points.iter().map(|&(_, y)| y);
+        let (min_x, max_x) = xs.fold((start.0, start.0), |(lo, hi), x| (lo.min(x), hi.max(x)));
+        let (min_y, max_y) = ys.fold((start.1, start.1), |(lo, hi), y| (lo.min(y), hi.max(y)));
+        let extent_x = (max_x - min_x).max(MIN_EXTENT_CM);
+        let extent_y = (max_y - min_y).max(MIN_EXTENT_CM);
+
+        let width = (f64::from(options.width) - 2.0 * MARGIN).max(1.0);
+        let height = (f64::from(options.height) - 2.0 * MARGIN).max(1.0);
+        let scale = (width / extent_x).min(height / extent_y);
+
+        // Center the path in the image
+        View {
+            min_x,
+            max_y,
+            scale,
+            offset_x: MARGIN + (width - (max_x - min_x) * scale) / 2.0,
+            offset_y: MARGIN + (height - (max_y - min_y) * scale) / 2.0,
+        }
+    }
+
+    fn point(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            self.offset_x + (x - self.min_x) * self.scale,
+            self.offset_y + (self.max_y - y) * self.scale,
+        )
+    }
+
+    fn pose(&self, keyframe: &Keyframe) -> (f64, f64) {
+        self.point(keyframe.pose.x, keyframe.pose.y)
+    }
+
+    /// The top-left corner and size of a rectangle in the image.
+    fn rect(&self, area: &Rect) -> (f64, f64, f64, f64) {
+        let (x, y) = self.point(area.x, area.y + area.height);
+        (x, y, area.width * self.scale, area.height * self.scale)
+    }
+
+    /// A round length in cm, and how many pixels it spans, for a bar about
+    /// 100 pixels long.
+    fn scale_bar(&self) -> (u32, f64) {
+        let target = 100.0 / self.scale;
+        let magnitude = 10f64.powf(target.log10().floor());
+        let length = [1.0, 2.0, 5.0, 10.0]
+            .iter()
+            .rev()
+            .map(|step| step * magnitude)
+            .find(|length| *length <= target)
+            .unwrap_or(magnitude)
+            .max(1.0);
+        (length as u32, length * self.scale)
+    }
+}
+
+/// Every corner of the arena's walls, boxes and goals.
+fn arena_points(arena: &Arena) -> Vec<(f64, f64)> {
+    let mut points = Vec::new();
+    for wall in &arena.walls {
+        points.push((wall.from[0], wall.from[1]));
+        points.push((wall.to[0], wall.to[1]));
+    }
+    let areas = arena.boxes.iter().cloned();
+    for area in areas.chain(arena.goals.iter().map(|goal| goal.area())) {
+        points.push((area.x, area.y));
+        points.push((area.x + area.width, area.y + area.height));
+    }
+    points
+}
+
+/// Draws goals as dashed green areas, boxes as grey blocks and walls as
+/// thick lines.
+fn draw_arena(svg: &mut String, arena: &Arena, view: &View) {
+    for goal in &arena.goals {
+        let (x, y, width, height) = view.rect(&goal.area());
+        let _ = writeln!(
+            svg,
+            r##"  <rect class="goal" x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="#d9f2d9" stroke="#2ca02c" stroke-dasharray="4 3"/><text x="{:.1}" y="{:.1}" fill="#2ca02c">{}</text>"##,
+            x,
+            y,
+            width,
+            height,
+            x + 4.0,
+            y + 14.0,
+            escape(&goal.name)
+        );
+    }
+    for area in &arena.boxes {
+        let (x, y, width, height) = view.rect(area);
+        let _ = writeln!(
+            svg,
+            r##"  <rect class="box" x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="#999999"/>"##,
+            x, y, width, height
+        );
+    }
+    for wall in &arena.walls {
+        let (x1, y1) = view.point(wall.from[0], wall.from[1]);
+        let (x2, y2) = view.point(wall.to[0], wall.to[1]);
+        let _ = writeln!(
+            svg,
+            r##"  <line class="wall" x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="#333333" stroke-width="4" stroke-linecap="round"/>"##,
+            x1, y1, x2, y2
+        );
+    }
+}
+
+/// Escapes text for use in SVG content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Section names in the order they first appear in the trace.
+fn sections(trace: &[Keyframe]) -> Vec<String> {
+    let mut sections: Vec<String> = Vec::new();
+    for keyframe in trace {
+        if !sections.contains(&keyframe.section) {
+            sections.push(keyframe.section.clone());
+        }
+    }
+    sections
+}
+
+/// Splits the trace into stretches that ran in one section. Each stretch
+/// after the first starts with the last keyframe of the one before it.
+fn runs(trace: &[Keyframe]) -> Vec<(&str, Vec<&Keyframe>)> {
+    let mut runs: Vec<(&str, Vec<&Keyframe>)> = Vec::new();
+    for keyframe in trace {
+        match runs.last_mut() {
+            Some((section, points)) if *section == keyframe.section => {
+                let last = points.last().expect("runs are never empty");
+                if last.pose.x != keyframe.pose.x || last.pose.y != keyframe.pose.y {
+                    points.push(keyframe);
+                }
+            }
+            Some((_, points)) => {
+                let last = *points.last().expect("runs are never empty");
+                runs.push((&keyframe.section, vec![last, keyframe]));
+            }
+            None => runs.push((&keyframe.section, vec![keyframe])),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::simulator::{simulate_program, SimulationOptions, StopReason};
+
+    fn render(input: &str) -> String {
+        let program = Parser::new(input.to_string()).parse().unwrap();
+        let simulation = simulate_program(&program, &SimulationOptions::default());
+        render_svg(&simulation, &RenderOptions::default())
+    }
+
+    #[test]
+    fn test_sections_and_turns() {
+        let svg = render(
+            r#"
+            turn:
+                mov right, 90deg
+            main:
+                mov forward, 2
+                jal turn
+                mov direction, 1
+                mov backward, 1
+            "#,
+        );
+
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"800\""));
+        // main, then turn, then main again
+        assert_eq!(svg.matches("class=\"section\"").count(), 3);
+        assert!(svg.contains("data-section=\"turn\" points=\""));
+        assert!(svg.contains("stroke=\"#ff7f0e\""));
+        assert!(svg.contains("<title>right turn, turn line 3</title>"));
+        assert!(svg.contains("<title>left turn, main line 7</title>"));
+        assert_eq!(svg.matches("class=\"turn\"").count(), 2);
+        assert!(svg.contains(">main</text>") && svg.contains(">turn</text>"));
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn test_png() {
+        let program = Parser::new("main:\n    mov left, 90deg\n".to_string())
+            .parse()
+            .unwrap();
+        let simulation = simulate_program(&program, &SimulationOptions::default());
+        let png = render_png(&simulation, &RenderOptions::default()).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[test]
+    fn test_scale_bar() {
+        // 40 cm forward is drawn at the one-metre minimum scale
+        let svg = render("main:\n    mov forward, 2\n");
+        assert!(svg.contains(">10 cm</text>"));
+        assert!(svg.contains("<circle class=\"start\" cx=\"296.0\" cy=\"300.0\""));
+    }
+
+    #[test]
+    fn test_empty_trace() {
+        let simulation = Simulation {
+            trace: Vec::new(),
+            duration_ms: 0,
+            steps: 0,
+            stop: StopReason::Finished,
+            collisions: Vec::new(),
+            goals: Vec::new(),
+        };
+        let svg = render_svg(&simulation, &RenderOptions::default());
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(!svg.contains("class=\"start\""));
+    }
+}
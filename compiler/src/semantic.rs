@@ -0,0 +1,310 @@
+use crate::diagnostic::{Diagnostic, Stage};
+use crate::ir::{Command, MoveKind, Operand, Program, Section, Unit, Variable};
+use crate::span::Span;
+use std::collections::HashMap;
+
+/// Variables visible at a point in the program: every global plus the
+/// locals of the current section declared so far.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    globals: HashMap<String, Span>,
+    locals: HashMap<String, Span>,
+}
+
+impl SymbolTable {
+    /// Declares a global, returning the span of an earlier declaration of
+    /// the same name if there is one.
+    pub fn declare_global(&mut self, name: &str, span: Span) -> Result<(), Span> {
+        match self.globals.get(name) {
+            Some(previous) => Err(*previous),
+            None => {
+                self.globals.insert(name.to_string(), span);
+                Ok(())
+            }
+        }
+    }
+
+    /// Declares a local in the current section, returning the span of an
+    /// earlier declaration in the same section if there is one.
+    pub fn declare_local(&mut self, name: &str, span: Span) -> Result<(), Span> {
+        match self.locals.get(name) {
+            Some(previous) => Err(*previous),
+            None => {
+                self.locals.insert(name.to_string(), span);
+                Ok(())
+            }
+        }
+    }
+
+    /// Where the global called `name` is declared, if there is one.
+    pub fn global(&self, name: &str) -> Option<Span> {
+        self.globals.get(name).copied()
+    }
+
+    /// Forgets the locals of the previous section.
+    pub fn enter_section(&mut self) {
+        self.locals.clear();
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.locals.contains_key(name) || self.globals.contains_key(name)
+    }
+}
+
+/// Checks a parsed program for errors the grammar cannot catch: undefined
+/// or duplicate variables, labels and sections, and section names that
+/// collide with the generated code.
+pub fn analyze(program: &Program) -> Vec<Diagnostic> {
+    let mut analyzer = Analyzer::default();
+    analyzer.check_program(program);
+    analyzer.diagnostics
+}
+
+/// Reports a missing `main` section, which a program needs before it can be
+/// lowered to a target that runs it.
+pub fn check_entry_point(program: &Program) -> Option<Diagnostic> {
+    if program
+        .sections
+        .iter()
+        .any(|section| section.name == "main")
+    {
+        return None;
+    }
+    Some(Diagnostic::error(
+        Stage::Semantic,
+        "Program has no 'main' section",
+        Span::new(0, 0, 1, 1),
+    ))
+}
+
+#[derive(Default)]
+struct Analyzer {
+    symbols: SymbolTable,
+    /// Every section in the program, by name
+    sections: HashMap<String, Span>,
+    /// Local labels of the section being checked
+    labels: HashMap<String, Span>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Analyzer {
+    fn check_program(&mut self, program: &Program) {
+        for Variable { name, value, span } in &program.globals {
+            self.check_operand(value, *span);
+            if let Err(previous) = self.symbols.declare_global(name, *span) {
+                self.error(
+                    format!("Variable '{}' is already declared at {}", name, previous),
+                    *span,
+                );
+            }
+        }
+
+        self.collect_sections(&program.sections);
+
+        for section in &program.sections {
+            self.symbols.enter_section();
+            self.collect_labels(&section.commands);
+            for command in &section.commands {
+                self.check_command(command);
+            }
+        }
+    }
+
+    /// Gathers section names up front so `jal` can call forward.
+    fn collect_sections(&mut self, sections: &[Section]) {
+        for section in sections {
+            let name = &section.name;
+            if let Some(previous) = self.sections.get(name) {
+                let message = format!("Section '{}' is already defined at {}", name, previous);
+                self.error(message, section.span);
+            } else {
+                self.sections.insert(name.clone(), section.span);
+            }
+        }
+    }
+
+    /// Gathers the section's labels up front so branches can jump forward.
+    fn collect_labels(&mut self, commands: &[Command]) {
+        self.labels.clear();
+        for command in commands {
+            if let Command::Label { name, span } = command {
+                if let Some(previous) = self.labels.get(name) {
+                    let message = format!("Label '.{}' is already defined at {}", name, previous);
+                    self.error(message, *span);
+                } else {
+                    self.labels.insert(name.clone(), *span);
+                }
+            }
+        }
+    }
+
+    fn check_command(&mut self, command: &Command) {
+        match command {
+            Command::Move {
+                r#type,
+                amount,
+                unit,
+                span,
+            } => {
+                self.check_operand(amount, *span);
+                if let Some(unit) = unit {
+                    if !r#type.units().contains(unit) {
+                        self.error(unit_mismatch(*r#type, *unit), *span);
+                    } else if let Operand::Variable(name) = amount {
+                        // Only reachable from hand-written IR
+                        self.error(
+                            format!("Unit '{}' cannot be used with variable '{}'", unit, name),
+                            *span,
+                        );
+                    }
+                }
+                match (r#type, amount) {
+                    (MoveKind::Direction, Operand::Literal(n)) if !(0..=2).contains(n) => {
+                        self.error(
+                            format!(
+                                "Invalid direction {}, expected 0 (straight), 1 (left) or 2 (right)",
+                                n
+                            ),
+                            *span,
+                        );
+                    }
+                    (MoveKind::Speed, Operand::Literal(n)) if !(0..=100).contains(n) => {
+                        self.error(
+                            format!("Invalid speed {}, expected a percentage from 0 to 100", n),
+                            *span,
+                        );
+                    }
+                    // Times and distances are turned into delays, which
+                    // cannot be negative
+                    (kind, Operand::Literal(n)) if *n < 0 && !kind.units().is_empty() => {
+                        self.error(
+                            format!(
+                                "'mov {}' cannot take a negative amount, found {}{}",
+                                kind,
+                                n,
+                                unit.map_or("", Unit::name)
+                            ),
+                            *span,
+                        );
+                    }
+                    // Delays are unsigned long milliseconds on the Arduino
+                    (kind, Operand::Literal(n))
+                        if unit.is_none()
+                            && !kind.units().is_empty()
+                            && i64::from(*n) > MAX_SECONDS =>
+                    {
+                        self.error(
+                            format!(
+                                "'mov {}' cannot take more than {} seconds, found {}",
+                                kind, MAX_SECONDS, n
+                            ),
+                            *span,
+                        );
+                    }
+                    _ => {}
+                }
+            }
+            Command::Var { name, value, span } => {
+                self.check_operand(value, *span);
+                // The backends and the simulator look locals up before
+                // globals for the whole section, so a local of the same name
+                // would change what earlier reads of the global see
+                if let Some(global) = self.symbols.global(name) {
+                    self.error(
+                        format!("Local '{}' shadows the global declared at {}", name, global),
+                        *span,
+                    );
+                } else if let Err(previous) = self.symbols.declare_local(name, *span) {
+                    self.error(
+                        format!("Variable '{}' is already declared at {}", name, previous),
+                        *span,
+                    );
+                }
+            }
+            Command::Add {
+                variable,
+                value,
+                span,
+            }
+            | Command::Sub {
+                variable,
+                value,
+                span,
+            } => {
+                if !self.symbols.is_defined(variable) {
+                    self.error(format!("Undefined variable '{}'", variable), *span);
+                }
+                self.check_operand(value, *span);
+            }
+            Command::Goto { label, span } => self.check_label(label, *span),
+            Command::Branch {
+                left,
+                right,
+                label,
+                span,
+                ..
+            } => {
+                self.check_operand(left, *span);
+                self.check_operand(right, *span);
+                self.check_label(label, *span);
+            }
+            Command::Repeat { count, body, span } => {
+                self.check_operand(count, *span);
+                for command in body {
+                    self.check_command(command);
+                }
+            }
+            Command::Jump { label, span } => {
+                if !self.sections.contains_key(label) {
+                    self.error(format!("Undefined section '{}'", label), *span);
+                }
+            }
+            Command::Label { .. } | Command::Return { .. } => {}
+        }
+    }
+
+    fn check_operand(&mut self, operand: &Operand, span: Span) {
+        if let Operand::Variable(name) = operand {
+            if !self.symbols.is_defined(name) {
+                self.error(format!("Undefined variable '{}'", name), span);
+            }
+        }
+    }
+
+    fn check_label(&mut self, label: &str, span: Span) {
+        if !self.labels.contains_key(label) {
+            self.error(format!("Undefined label '.{}'", label), span);
+        }
+    }
+
+    fn error(&mut self, message: String, span: Span) {
+        self.diagnostics
+            .push(Diagnostic::error(Stage::Semantic, message, span));
+    }
+}
+
+/// Explains which units a move takes when it was given a different one.
+/// The longest time in whole seconds that still fits in an Arduino's
+/// `unsigned long` milliseconds.
+const MAX_SECONDS: i64 = u32::MAX as i64 / 1000;
+
+fn unit_mismatch(kind: MoveKind, unit: Unit) -> String {
+    let units = kind.units();
+    if units.is_empty() {
+        return format!("'mov {}' does not take a unit, found '{}'", kind, unit);
+    }
+    // Every move with units takes times, which can be written many ways
+    let expected: Vec<&str> = units
+        .iter()
+        .map(|unit| match unit {
+            Unit::Ms => "a time",
+            unit => unit.name(),
+        })
+        .collect();
+    format!(
+        "'mov {}' takes {}, not '{}'",
+        kind,
+        expected.join(" or "),
+        unit
+    )
+}
@@ -0,0 +1,84 @@
+//! Settings files, like robot profiles and arenas, read from TOML or JSON.
+
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+/// Why a settings file was rejected. `what` names the kind of file, like
+/// `profile` or `arena`.
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("Failed to read {what}: {source}")]
+    Io {
+        what: &'static str,
+        source: std::io::Error,
+    },
+    #[error("Invalid TOML {what}: {source}")]
+    Toml {
+        what: &'static str,
+        source: toml::de::Error,
+    },
+    #[error("Invalid JSON {what}: {source}")]
+    Json {
+        what: &'static str,
+        source: serde_json::Error,
+    },
+    #[error("The {what} '{path}' must be a .toml or .json file")]
+    UnknownFormat { what: &'static str, path: String },
+    #[error("Invalid {what}: {message}")]
+    Invalid { what: &'static str, message: String },
+}
+
+/// A type read from a settings file, checked once it is deserialized.
+pub(crate) trait Settings: DeserializeOwned {
+    /// The kind of file, for error messages
+    const WHAT: &'static str;
+
+    /// Checks what deserializing cannot, like values that must be positive.
+    fn validate(&self) -> Result<(), SettingsError>;
+
+    /// A `SettingsError::Invalid` for this kind of file.
+    fn invalid(message: String) -> SettingsError {
+        SettingsError::Invalid {
+            what: Self::WHAT,
+            message,
+        }
+    }
+}
+
+pub(crate) fn from_toml<T: Settings>(source: &str) -> Result<T, SettingsError> {
+    let settings: T = toml::from_str(source).map_err(|source| SettingsError::Toml {
+        what: T::WHAT,
+        source,
+    })?;
+    settings.validate()?;
+    Ok(settings)
+}
+
+pub(crate) fn from_json<T: Settings>(source: &str) -> Result<T, SettingsError> {
+    let settings: T = serde_json::from_str(source).map_err(|source| SettingsError::Json {
+        what: T::WHAT,
+        source,
+    })?;
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// Loads a settings file, picking the format from the file extension.
+pub(crate) fn load<T: Settings>(path: &Path) -> Result<T, SettingsError> {
+    let read = || {
+        fs::read_to_string(path).map_err(|source| SettingsError::Io {
+            what: T::WHAT,
+            source,
+        })
+    };
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => from_toml(&read()?),
+        Some("json") => from_json(&read()?),
+        _ => Err(SettingsError::UnknownFormat {
+            what: T::WHAT,
+            path: path.display().to_string(),
+        }),
+    }
+}
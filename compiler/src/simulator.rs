@@ -0,0 +1,724 @@
+use crate::arena::Arena;
+use crate::callgraph::CallGraph;
+use crate::ir::{Condition, MoveKind, Operand, Program, Unit};
+use crate::lower::{lower, Instruction, LoweredSection};
+use crate::profile::RobotProfile;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Where the car is: position in centimetres and heading in degrees,
+/// counter-clockwise from the x axis, so left turns increase it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Pose {
+    pub x: f64,
+    pub y: f64,
+    pub heading: f64,
+}
+
+/// What the drive motor (`in1`/`in2`) is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Drive {
+    Stopped,
+    Forward,
+    Backward,
+}
+
+/// Where the steering motor (`in3`/`in4`) holds the front wheels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Steering {
+    Straight,
+    Left,
+    Right,
+}
+
+/// The car at one point in time, and the source line it was running.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe {
+    pub time_ms: u64,
+    #[serde(flatten)]
+    pub pose: Pose,
+    pub drive: Drive,
+    pub steering: Steering,
+    /// Motor power as set by `mov speed`, in percent
+    pub speed: u8,
+    pub section: String,
+    pub line: usize,
+}
+
+/// The car running into an obstacle of the arena.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Collision {
+    pub time_ms: u64,
+    /// Where the car stopped, short of the obstacle
+    pub x: f64,
+    pub y: f64,
+    /// The obstacle that was hit, e.g. `wall 2` or `box 1`, counting from
+    /// one in the order the arena lists them
+    pub obstacle: String,
+}
+
+/// The first time the car's center entered a goal of the arena.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GoalReached {
+    pub name: String,
+    pub time_ms: u64,
+}
+
+/// Why a simulation ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// `main` returned, and `loop_main` was off
+    Finished,
+    /// `max_duration_ms` of robot time went by
+    TimeLimit,
+    /// `max_steps` instructions ran
+    StepLimit,
+    /// The trace reached `max_keyframes` keyframes
+    KeyframeLimit,
+    /// Recursion went deeper than the non-blocking sketch's frame stack,
+    /// where the robot halts
+    StackOverflow,
+}
+
+/// Settings for a simulation run.
+#[derive(Debug, Clone)]
+pub struct SimulationOptions {
+    /// Timings and calibrated speeds of the simulated robot
+    pub profile: RobotProfile,
+    pub start: Pose,
+    /// Obstacles and goals around the car. Its start pose replaces `start`
+    pub arena: Option<Arena>,
+    /// Restart `main` when it returns, like the Arduino `loop()`
+    pub loop_main: bool,
+    /// Robot time after which the simulation stops
+    pub max_duration_ms: u64,
+    /// Instructions after which the simulation stops, so programs that
+    /// loop without moving still end
+    pub max_steps: usize,
+    /// Time between keyframes while the car is moving
+    pub sample_ms: u64,
+    /// Keyframes after which the simulation stops. Every move adds a few
+    /// whatever `sample_ms` is, so this is what bounds the trace
+    pub max_keyframes: usize,
+}
+
+impl Default for SimulationOptions {
+    fn default() -> Self {
+        SimulationOptions {
+            profile: RobotProfile::default(),
+            start: Pose::default(),
+            arena: None,
+            loop_main: false,
+            max_duration_ms: 10 * 60 * 1000,
+            max_steps: 1_000_000,
+            sample_ms: 100,
+            max_keyframes: 100_000,
+        }
+    }
+}
+
+/// The result of a simulation run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Simulation {
+    /// The car's state whenever it changed, and every `sample_ms` while it
+    /// moves, in time order
+    pub trace: Vec<Keyframe>,
+    pub duration_ms: u64,
+    /// Instructions run
+    pub steps: usize,
+    pub stop: StopReason,
+    /// Every time the car ran into an obstacle. It stays blocked, with the
+    /// program carrying on, until it drives away again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collisions: Vec<Collision>,
+    /// The goals the car reached, in the order it reached them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub goals: Vec<GoalReached>,
+}
+
+impl Simulation {
+    /// When the car first hit something, if it did.
+    pub fn first_collision_ms(&self) -> Option<u64> {
+        self.collisions.first().map(|collision| collision.time_ms)
+    }
+
+    /// When the car reached the goal called `name`, if it did.
+    pub fn goal_reached_ms(&self, name: &str) -> Option<u64> {
+        self.goals
+            .iter()
+            .find(|goal| goal.name == name)
+            .map(|goal| goal.time_ms)
+    }
+}
+
+/// Longest stretch of driving between collision checks, short enough that
+/// the car cannot pass through a wall.
+const COLLISION_STEP_MS: u64 = 10;
+
+/// Runs a checked program against a kinematic model of the car.
+///
+/// The drive motor moves the car at the profile's `cm_per_second` (scaled
+/// by `mov speed`) and, with the wheels at full lock, turns it at
+/// `degrees_per_second`. Moves wait exactly as the generated sketch does,
+/// including the settle and steering delays, so the trace's timing matches
+/// the real robot.
+pub(crate) fn simulate_program(program: &Program, options: &SimulationOptions) -> Simulation {
+    let mut simulator = Simulator::new(program, options.clone());
+    while simulator.stop.is_none() {
+        simulator.step();
+    }
+    simulator.finish()
+}
+
+/// An interpreter over the lowered program, which the debugger also drives
+/// one instruction at a time.
+pub(crate) struct Simulator {
+    pub(crate) sections: Vec<LoweredSection>,
+    options: SimulationOptions,
+    pub(crate) globals: HashMap<String, i32>,
+    pub(crate) stack: Vec<Frame>,
+    stack_size: usize,
+    pose: Pose,
+    drive: Drive,
+    steering: Steering,
+    speed: u8,
+    time_ms: u64,
+    steps: usize,
+    trace: Vec<Keyframe>,
+    pub(crate) stop: Option<StopReason>,
+    collisions: Vec<Collision>,
+    goals: Vec<GoalReached>,
+    /// Whether the car is pushing against an obstacle
+    blocked: bool,
+}
+
+pub(crate) struct Frame {
+    pub(crate) section: usize,
+    pub(crate) next: usize,
+    /// The section's locals, then its `repeat` counters
+    pub(crate) locals: Vec<i32>,
+}
+
+/// A stretch of a move: the motor states to set, then how long to wait.
+struct Phase {
+    drive: Option<Drive>,
+    steering: Option<Steering>,
+    duration_ms: u64,
+}
+
+impl Simulator {
+    pub(crate) fn new(program: &Program, options: SimulationOptions) -> Self {
+        let mut simulator = Simulator {
+            sections: lower(program).expect("checked programs define their labels"),
+            globals: HashMap::new(),
+            stack: Vec::new(),
+            stack_size: CallGraph::new(program).stack_size("main"),
+            pose: options
+                .arena
+                .as_ref()
+                .map_or(options.start, |arena| arena.start),
+            drive: Drive::Stopped,
+            steering: Steering::Straight,
+            speed: 100,
+            time_ms: 0,
+            steps: 0,
+            trace: Vec::new(),
+            stop: None,
+            collisions: Vec::new(),
+            goals: Vec::new(),
+            blocked: false,
+            options,
+        };
+        for variable in &program.globals {
+            let value = simulator.value(&variable.value);
+            simulator.globals.insert(variable.name.clone(), value);
+        }
+        simulator.call("main");
+        simulator.check_goals(0);
+        simulator.record();
+        simulator
+    }
+
+    /// Runs one instruction. Does nothing once the simulation has stopped.
+    pub(crate) fn step(&mut self) {
+        if self.stop.is_some() {
+            return;
+        }
+        if self.steps == self.options.max_steps {
+            self.stop = Some(StopReason::StepLimit);
+            return;
+        }
+        let Some(frame) = self.stack.last_mut() else {
+            if self.options.loop_main {
+                self.call("main");
+            } else {
+                self.stop = Some(StopReason::Finished);
+            }
+            return;
+        };
+
+        let step = &self.sections[frame.section].steps[frame.next];
+        let instruction = step.instruction.clone();
+        frame.next += 1;
+        self.steps += 1;
+
+        match instruction {
+            Instruction::Move { kind, amount, unit } => self.run_move(kind, &amount, unit),
+            Instruction::Set { variable, value } => {
+                let value = self.value(&value);
+                self.set(&variable, value);
+            }
+            Instruction::Add { variable, value } => {
+                let value = self
+                    .value(&Operand::Variable(variable.clone()))
+                    .wrapping_add(self.value(&value));
+                self.set(&variable, value);
+            }
+            Instruction::Sub { variable, value } => {
+                let value = self
+                    .value(&Operand::Variable(variable.clone()))
+                    .wrapping_sub(self.value(&value));
+                self.set(&variable, value);
+            }
+            Instruction::Jump { target } => self.jump(target),
+            Instruction::Branch {
+                condition,
+                left,
+                right,
+                target,
+            } => {
+                let (left, right) = (self.value(&left), self.value(&right));
+                let taken = match condition {
+                    Condition::Equal => left == right,
+                    Condition::NotEqual => left != right,
+                    Condition::Less => left < right,
+                    Condition::GreaterOrEqual => left >= right,
+                };
+                if taken {
+                    self.jump(target);
+                }
+            }
+            Instruction::Call { section } => self.call(&section),
+            Instruction::Return => {
+                self.stack.pop();
+            }
+            Instruction::StartLoop { counter, count } => {
+                let count = self.value(&count);
+                *self.counter(counter) = count;
+            }
+            Instruction::Loop { counter, exit } => {
+                let counter = self.counter(counter);
+                if *counter <= 0 {
+                    self.jump(exit);
+                } else {
+                    *counter -= 1;
+                }
+            }
+        }
+    }
+
+    /// Returns the trace of a stopped run.
+    pub(crate) fn finish(&mut self) -> Simulation {
+        self.record();
+        Simulation {
+            trace: self.trace.clone(),
+            duration_ms: self.time_ms,
+            steps: self.steps,
+            stop: self.stop.expect("only stopped simulations finish"),
+            collisions: self.collisions.clone(),
+            goals: self.goals.clone(),
+        }
+    }
+
+    fn call(&mut self, name: &str) {
+        if self.stack.len() == self.stack_size {
+            self.drive = Drive::Stopped;
+            self.steering = Steering::Straight;
+            self.stop = Some(StopReason::StackOverflow);
+            return;
+        }
+        let section = self
+            .sections
+            .iter()
+            .position(|section| section.name == name)
+            .expect("checked programs only call defined sections");
+        let lowered = &self.sections[section];
+        self.stack.push(Frame {
+            section,
+            next: 0,
+            locals: vec![0; lowered.locals.len() + lowered.counters],
+        });
+    }
+
+    fn jump(&mut self, target: usize) {
+        if let Some(frame) = self.stack.last_mut() {
+            frame.next = target;
+        }
+    }
+
+    /// The slot of a local in the current frame, if `name` is one.
+    fn local(&mut self, name: &str) -> Option<&mut i32> {
+        let frame = self.stack.last_mut()?;
+        let index = self.sections[frame.section]
+            .locals
+            .iter()
+            .position(|local| local == name)?;
+        Some(&mut frame.locals[index])
+    }
+
+    fn counter(&mut self, counter: usize) -> &mut i32 {
+        let frame = self.stack.last_mut().expect("loops run inside a section");
+        let index = self.sections[frame.section].locals.len() + counter;
+        &mut frame.locals[index]
+    }
+
+    fn value(&mut self, operand: &Operand) -> i32 {
+        match operand {
+            Operand::Literal(n) => *n,
+            Operand::Variable(name) => match self.local(name) {
+                Some(value) => *value,
+                None => self.globals.get(name).copied().unwrap_or(0),
+            },
+        }
+    }
+
+    fn set(&mut self, name: &str, value: i32) {
+        match self.local(name) {
+            Some(slot) => *slot = value,
+            None => {
+                self.globals.insert(name.to_string(), value);
+            }
+        }
+    }
+
+    fn run_move(&mut self, kind: MoveKind, amount: &Operand, unit: Option<Unit>) {
+        let value = self.value(amount);
+        if kind == MoveKind::Speed {
+            self.speed = value.clamp(0, 100) as u8;
+            self.record();
+            return;
+        }
+
+        // Negative variable amounts move for no time, as `seconds_ms` makes
+        // the blocking helpers do
+        let mut duration = self.options.profile.duration_ms(value, unit).max(0) as u64;
+        // Calibrated amounts take longer at lower speeds, as `at_speed` does
+        if matches!(unit, Some(Unit::Cm | Unit::Deg)) && self.speed > 0 {
+            duration = duration * 100 / u64::from(self.speed);
+        }
+        for phase in self.move_phases(kind, value, duration) {
+            self.drive = phase.drive.unwrap_or(self.drive);
+            self.steering = phase.steering.unwrap_or(self.steering);
+            self.record();
+            if self.stop.is_none() {
+                self.advance(phase.duration_ms);
+            }
+            if self.stop.is_some() {
+                return;
+            }
+        }
+    }
+
+    /// The same motor changes and pauses as the Arduino helpers.
+    fn move_phases(&self, kind: MoveKind, value: i32, duration: u64) -> Vec<Phase> {
+        let settle = u64::from(self.options.profile.timing.settle_ms);
+        let turn = u64::from(self.options.profile.timing.turn_ms);
+        let phase = |drive, steering, duration_ms| Phase {
+            drive,
+            steering,
+            duration_ms,
+        };
+        let steer = |side| {
+            let mut phases = vec![phase(None, Some(Steering::Straight), turn)];
+            if side != Steering::Straight {
+                phases.push(phase(None, None, settle));
+                phases.push(phase(None, Some(side), turn));
+            }
+            phases
+        };
+        let drive = |direction| {
+            vec![
+                phase(None, None, settle),
+                phase(Some(direction), None, duration),
+                phase(Some(Drive::Stopped), None, settle),
+            ]
+        };
+
+        match kind {
+            MoveKind::Forward => drive(Drive::Forward),
+            MoveKind::Backward => drive(Drive::Backward),
+            MoveKind::Wait => vec![phase(Some(Drive::Stopped), None, duration)],
+            MoveKind::Direction => steer(match value {
+                1 => Steering::Left,
+                2 => Steering::Right,
+                _ => Steering::Straight,
+            }),
+            MoveKind::Left | MoveKind::Right => {
+                let side = if kind == MoveKind::Left {
+                    Steering::Left
+                } else {
+                    Steering::Right
+                };
+                let mut phases = steer(side);
+                phases.extend(drive(Drive::Forward));
+                phases.extend(steer(Steering::Straight));
+                phases
+            }
+            MoveKind::Speed => Vec::new(),
+        }
+    }
+
+    /// Lets `duration_ms` pass with the motors as they are, stopping at
+    /// the time limit.
+    fn advance(&mut self, duration_ms: u64) {
+        let end = self.time_ms.saturating_add(duration_ms);
+        let limit = self.options.max_duration_ms;
+        let moving = self.drive != Drive::Stopped;
+        while self.time_ms < end.min(limit) {
+            let next = if moving {
+                (self.time_ms + self.options.sample_ms.max(1))
+                    .min(end)
+                    .min(limit)
+            } else {
+                end.min(limit)
+            };
+            self.drive_for(next - self.time_ms);
+            self.time_ms = next;
+            if moving {
+                self.record();
+                if self.stop.is_some() {
+                    return;
+                }
+            }
+        }
+        if end > limit {
+            self.stop = Some(StopReason::TimeLimit);
+        }
+    }
+
+    /// Drives the car for `elapsed_ms`, stopping it short of any obstacle
+    /// in the arena.
+    fn drive_for(&mut self, elapsed_ms: u64) {
+        if self.options.arena.is_none() {
+            self.pose = self.moved(elapsed_ms);
+            return;
+        }
+
+        let mut done = 0;
+        while done < elapsed_ms {
+            let step = COLLISION_STEP_MS.min(elapsed_ms - done);
+            done += step;
+            let pose = self.moved(step);
+            let arena = self.options.arena.as_ref().expect("checked above");
+            match arena.obstacle_at(pose.x, pose.y) {
+                Some(obstacle) => {
+                    if !self.blocked {
+                        self.collisions.push(Collision {
+                            time_ms: self.time_ms + done,
+                            x: self.pose.x,
+                            y: self.pose.y,
+                            obstacle,
+                        });
+                    }
+                    self.blocked = true;
+                }
+                None => {
+                    self.pose = pose;
+                    self.blocked = false;
+                    self.check_goals(self.time_ms + done);
+                }
+            }
+        }
+    }
+
+    /// Notes the goals the car is in, if it had not reached them before.
+    fn check_goals(&mut self, time_ms: u64) {
+        let Some(arena) = &self.options.arena else {
+            return;
+        };
+        for goal in &arena.goals {
+            let reached = self.goals.iter().any(|reached| reached.name == goal.name);
+            if !reached && goal.area().contains(self.pose.x, self.pose.y) {
+                self.goals.push(GoalReached {
+                    name: goal.name.clone(),
+                    time_ms,
+                });
+            }
+        }
+    }
+
+    /// Where the car ends up after driving along its arc for `elapsed_ms`.
+    fn moved(&self, elapsed_ms: u64) -> Pose {
+        let direction = match self.drive {
+            Drive::Stopped => return self.pose,
+            Drive::Forward => 1.0,
+            Drive::Backward => -1.0,
+        };
+        let calibration = &self.options.profile.calibration;
+        let seconds = elapsed_ms as f64 / 1000.0;
+        let power = f64::from(self.speed) / 100.0;
+        let distance = direction * calibration.cm_per_second * power * seconds;
+        // Reversing with the wheels turned swings the car the other way
+        let turn = match self.steering {
+            Steering::Straight => 0.0,
+            Steering::Left => direction * calibration.degrees_per_second * power * seconds,
+            Steering::Right => -direction * calibration.degrees_per_second * power * seconds,
+        };
+
+        let mut pose = self.pose;
+        let heading = pose.heading.to_radians();
+        if turn == 0.0 {
+            pose.x += distance * heading.cos();
+            pose.y += distance * heading.sin();
+        } else {
+            let radius = distance / turn.to_radians();
+            let end = heading + turn.to_radians();
+            pose.x += radius * (end.sin() - heading.sin());
+            pose.y -= radius * (end.cos() - heading.cos());
+        }
+        pose.heading = (pose.heading + turn).rem_euclid(360.0);
+        pose
+    }
+
+    /// Adds a keyframe for the current state, replacing one taken at the
+    /// same time, and stops the run once the trace is full.
+    fn record(&mut self) {
+        let keyframe = self.keyframe();
+        match self.trace.last_mut() {
+            Some(last) if last.time_ms == keyframe.time_ms => *last = keyframe,
+            _ => self.trace.push(keyframe),
+        }
+        if self.trace.len() >= self.options.max_keyframes {
+            self.stop.get_or_insert(StopReason::KeyframeLimit);
+        }
+    }
+
+    /// The car now, and the line of the instruction that ran last.
+    pub(crate) fn keyframe(&self) -> Keyframe {
+        let (section, line) = match self.stack.last() {
+            Some(frame) => {
+                let section = &self.sections[frame.section];
+                let step = frame.next.saturating_sub(1).min(section.steps.len() - 1);
+                (section.name.clone(), section.steps[step].span.line)
+            }
+            None => ("main".to_string(), 0),
+        };
+        Keyframe {
+            time_ms: self.time_ms,
+            pose: self.pose,
+            drive: self.drive,
+            steering: self.steering,
+            speed: self.speed,
+            section,
+            line,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn run(input: &str, options: SimulationOptions) -> Simulation {
+        let program = Parser::new(input.to_string()).parse().unwrap();
+        simulate_program(&program, &options)
+    }
+
+    #[test]
+    fn test_forward_matches_sketch_timing() {
+        let simulation = run("main:\n    mov forward, 2\n", SimulationOptions::default());
+
+        assert_eq!(simulation.stop, StopReason::Finished);
+        // Settle, drive for two seconds, settle
+        assert_eq!(simulation.duration_ms, 3000);
+        let last = simulation.trace.last().unwrap();
+        assert!((last.pose.x - 40.0).abs() < 1e-9);
+        assert!(last.pose.y.abs() < 1e-9);
+        assert_eq!(last.drive, Drive::Stopped);
+
+        let driving: Vec<_> = simulation
+            .trace
+            .iter()
+            .filter(|keyframe| keyframe.drive == Drive::Forward)
+            .map(|keyframe| keyframe.time_ms)
+            .collect();
+        // The keyframe at 2500 ms is the stop, which replaces the last sample
+        assert_eq!(driving.first(), Some(&500));
+        assert_eq!(driving.last(), Some(&2400));
+        assert_eq!(driving.len(), 20);
+    }
+
+    #[test]
+    fn test_turn_and_speed() {
+        let simulation = run(
+            "main:\n    mov speed, 50\n    mov left, 90deg\n",
+            SimulationOptions::default(),
+        );
+
+        // 90deg takes two seconds at full power, so four at half
+        let last = simulation.trace.last().unwrap();
+        assert!((last.pose.heading - 90.0).abs() < 1e-9);
+        assert_eq!(last.steering, Steering::Straight);
+        assert_eq!(last.speed, 50);
+        assert!(last.pose.x > 0.0 && last.pose.y > 0.0);
+        let turning = simulation
+            .trace
+            .iter()
+            .filter(|keyframe| keyframe.drive == Drive::Forward)
+            .count();
+        assert_eq!(turning, 40);
+
+        // A time is not stretched, so half power turns half as far
+        let simulation = run(
+            "main:\n    mov speed, 50\n    mov left, 2\n",
+            SimulationOptions::default(),
+        );
+        let last = simulation.trace.last().unwrap();
+        assert!((last.pose.heading - 45.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_limits() {
+        let simulation = run(
+            "main:\n.top:\n    j .top\n",
+            SimulationOptions {
+                max_steps: 100,
+                ..SimulationOptions::default()
+            },
+        );
+        assert_eq!(simulation.stop, StopReason::StepLimit);
+        assert_eq!(simulation.steps, 100);
+
+        let simulation = run(
+            "main:\n    mov forward, 1\n",
+            SimulationOptions {
+                loop_main: true,
+                max_duration_ms: 10_000,
+                ..SimulationOptions::default()
+            },
+        );
+        assert_eq!(simulation.stop, StopReason::TimeLimit);
+        assert_eq!(simulation.duration_ms, 10_000);
+
+        // Short waits add keyframes however rarely the car is sampled
+        let simulation = run(
+            "main:\n.top:\n    mov wait, 1ms\n    j .top\n",
+            SimulationOptions {
+                max_keyframes: 50,
+                sample_ms: 1000,
+                ..SimulationOptions::default()
+            },
+        );
+        assert_eq!(simulation.stop, StopReason::KeyframeLimit);
+        assert_eq!(simulation.trace.len(), 50);
+        assert_eq!(
+            simulation.trace.last().unwrap().time_ms,
+            simulation.duration_ms
+        );
+    }
+}
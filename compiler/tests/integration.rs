@@ -1,4 +1,10 @@
-use compiler::{compile, compile_to_arduino};
+use compiler::{
+    check, compile, compile_ir_to_arduino, compile_ir_to_with, compile_to, compile_to_arduino,
+    compile_to_with, debug, generate, ir_schema, render_svg, simulate, Arena, CodegenOptions,
+    CompilerError, DebugRequest, Drive, Parser, RenderOptions, RobotProfile, Severity,
+    SimulationOptions, Span, Stage, StateMachineBackend, Steering, StopReason, IR_VERSION,
+    MAX_CALL_DEPTH,
+};
 
 #[test]
 fn test_compile_simple_program() {
@@ -12,6 +18,7 @@ fn test_compile_simple_program() {
 
     let output = compile(input).unwrap();
     let expected = r#"{
+  "version": 2,
   "sections": [
     {
       "name": "circle",
@@ -58,7 +65,7 @@ fn test_compile_to_arduino() {
     .to_string();
 
     let output = compile_to_arduino(input).unwrap();
-    assert!(output.contains("void circle()"));
+    assert!(output.contains("void section_circle()"));
     assert!(output.contains("void main_loop()"));
     assert!(output.contains("forward(4)"));
     assert!(output.contains("circle();"));
@@ -83,6 +90,7 @@ fn test_compile_multiple_sections() {
 
     let output = compile(input).unwrap();
     let expected = r#"{
+  "version": 2,
   "sections": [
     {
       "name": "section1",
@@ -148,6 +156,7 @@ fn test_compile_empty_section() {
 
     let output = compile(input).unwrap();
     let expected = r#"{
+  "version": 2,
   "sections": [
     {
       "name": "empty",
@@ -174,3 +183,1457 @@ fn test_compile_invalid_syntax() {
         .to_string()
         .contains("Expected ',' after direction"));
 }
+
+#[test]
+fn test_compile_error_span() {
+    let input = "main:\n    jal circle\n    mov forward 10\n".to_string();
+
+    let error = compile(input).unwrap_err();
+    assert_eq!(error.span(), Some(Span::new(37, 39, 3, 17)));
+    assert_eq!(
+        error.to_string(),
+        "Parser error at 3:17: Expected ',' after direction"
+    );
+}
+
+#[test]
+fn test_compile_integer_out_of_range() {
+    let input = r#"
+    main:
+        mov forward, 99999999999
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input).unwrap_err();
+    assert_eq!(error.diagnostics().len(), 1);
+    assert_eq!(error.diagnostics()[0].stage, Stage::Lexer);
+    assert_eq!(error.span().map(|span| span.line), Some(3));
+    assert!(error
+        .to_string()
+        .contains("Integer literal '99999999999' is out of range"));
+}
+
+#[test]
+fn test_lexer_error_does_not_spill_into_next_line() {
+    for bad in ["@", "0x1_0000_0000"] {
+        let input = format!("main:\n    mov forward, {}\n    mov forward, 1\n", bad);
+
+        // The bad amount is consumed, so the valid line after it parses
+        let error = compile_to_arduino(input).unwrap_err();
+        assert_eq!(error.diagnostics().len(), 1);
+        assert_eq!(error.diagnostics()[0].stage, Stage::Lexer);
+        assert_eq!(error.span().map(|span| span.line), Some(2));
+    }
+}
+
+#[test]
+fn test_compile_with_comments() {
+    let input = r#"
+    ; Drive a small loop
+    circle:
+        mov direction, 1    # Turn left
+        mov forward, 4      # Move forward for 4 seconds
+        mov direction, 0    # Go straight
+
+    main:
+        jal circle          ; Call the circle function
+    "#
+    .to_string();
+
+    let output = compile_to_arduino(input).unwrap();
+    assert!(output.contains("void section_circle()"));
+    assert!(output.contains("forward(4)"));
+    assert!(output.contains("circle();"));
+}
+
+#[test]
+fn test_compile_reports_every_error() {
+    let input = r#"
+    circle:
+        mov direction 1
+        mov forward, 4
+        mov wait,
+    main:
+        jal 5
+        spin circle
+        jal circle
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input).unwrap_err();
+    let diagnostics = error.diagnostics();
+    let lines: Vec<usize> = diagnostics.iter().map(|d| d.span.line).collect();
+    assert_eq!(lines, vec![3, 5, 7, 8]);
+    assert!(diagnostics
+        .iter()
+        .all(|d| d.severity == Severity::Error && d.stage == Stage::Parser));
+    assert_eq!(diagnostics[0].message, "Expected ',' after direction");
+    assert_eq!(diagnostics[1].message, "Expected number or variable");
+    assert_eq!(diagnostics[2].message, "Expected label after jal");
+    assert_eq!(diagnostics[3].message, "Unknown instruction 'spin'");
+}
+
+#[test]
+fn test_missing_operand_at_end_of_line() {
+    let input = "main:\n    var i = 0\n    jal\n    mov direction, 1\n    mov forward,\n    mov forward, 1\n    add i,\n    sub\n    add i, 1\n.top:\n    bne i, 3,\n    j .top\n    blt i,\n    j\n";
+
+    // Each error is on the line missing the operand, and the next line is
+    // left alone
+    let error = compile(input.to_string()).unwrap_err();
+    let messages: Vec<(usize, usize, &str)> = error
+        .diagnostics()
+        .iter()
+        .map(|d| (d.span.line, d.span.column, d.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (3, 8, "Expected label after jal"),
+            (5, 17, "Expected number or variable"),
+            (7, 11, "Expected number or variable"),
+            (8, 8, "Expected variable name after sub"),
+            (11, 14, "Expected local label like '.loop'"),
+            (13, 11, "Expected number or variable"),
+            (14, 6, "Expected local label like '.loop'"),
+        ]
+    );
+}
+
+#[test]
+fn test_compile_return() {
+    let input = r#"
+    circle:
+        mov direction, 1
+        mov forward, 4
+        ret
+        mov direction, 0
+
+    main:
+        jal circle
+        ret
+    "#
+    .to_string();
+
+    let output = compile_to_arduino(input.clone()).unwrap();
+    assert!(output.contains("    forward(4);\n    return;\n    straight();\n"));
+    assert!(output.contains("    section_circle();\n    return; // restart main\n"));
+
+    let ir = compile(input).unwrap();
+    assert!(ir.contains("\"Return\": {}"));
+}
+
+#[test]
+fn test_compile_variables() {
+    let input = r#"
+    var distance = 4
+    var turn = 1
+
+    main:
+        var pause = 2
+        mov direction, turn
+        mov forward, distance
+        mov wait, pause
+    "#
+    .to_string();
+
+    let output = compile_to_arduino(input.clone()).unwrap();
+    assert!(output.contains("long var_distance = 4;\nlong var_turn = 1;\n"));
+    assert!(output.contains("void main_loop() {\n    long var_pause = 0;\n    var_pause = 2;\n"));
+    assert!(output.contains("    steer(var_turn);\n"));
+    assert!(output.contains("    forward(var_distance);\n"));
+    assert!(output.contains("    wait(var_pause);\n"));
+    // Variables are 32-bit, wider than an AVR int
+    assert!(output.contains("void forward(long time){"));
+    assert!(output.contains("void backwards(long time){"));
+    assert!(output.contains("void wait(long time){"));
+
+    let ir = compile(input).unwrap();
+    assert!(ir.contains("\"globals\": ["));
+    assert!(ir.contains("\"amount\": \"distance\""));
+}
+
+#[test]
+fn test_local_cannot_shadow_global() {
+    let input = "var speed = 5\n\nmain:\n    var speed = speed\n    mov forward, speed\n";
+
+    let error = compile_to_arduino(input.to_string()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Semantic error at 4:5: Local 'speed' shadows the global declared at 1:1"
+    );
+    assert!(simulate(input.to_string(), &SimulationOptions::default()).is_err());
+}
+
+#[test]
+fn test_compile_undefined_variable() {
+    let input = r#"
+    var speed = 5
+    var speed = 6
+
+    circle:
+        var radius = 3
+
+    main:
+        mov forward, radius
+    "#
+    .to_string();
+
+    let error = compile(input).unwrap_err();
+    let diagnostics = error.diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.iter().all(|d| d.stage == Stage::Semantic));
+    assert_eq!(
+        diagnostics[0].message,
+        "Variable 'speed' is already declared at 2:5"
+    );
+    assert_eq!(diagnostics[1].message, "Undefined variable 'radius'");
+    assert_eq!(diagnostics[1].span.line, 9);
+}
+
+#[test]
+fn test_compile_arithmetic() {
+    let input = r#"
+    var distance = 1
+
+    main:
+        var step = 2
+        add distance, step
+        sub distance, 1
+        mov forward, distance
+    "#
+    .to_string();
+
+    let output = compile_to_arduino(input.clone()).unwrap();
+    assert!(output.contains("    var_distance = wrapping_add(var_distance, var_step);\n"));
+    assert!(output.contains("    var_distance = wrapping_sub(var_distance, 1);\n"));
+    assert!(output.contains("long wrapping_add(long a, long b){"));
+
+    let ir = compile(input).unwrap();
+    assert!(ir.contains(
+        r#""Add": {
+            "variable": "distance",
+            "value": "step"
+          }"#
+    ));
+    assert!(ir.contains(
+        r#""Sub": {
+            "variable": "distance",
+            "value": 1
+          }"#
+    ));
+}
+
+#[test]
+fn test_compile_arithmetic_errors() {
+    let input = r#"
+    main:
+        add 5, 1
+        sub missing, 1
+        add
+    "#
+    .to_string();
+
+    let error = compile(input).unwrap_err();
+    let messages: Vec<&str> = error
+        .diagnostics()
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Expected variable name after add",
+            "Expected variable name after add"
+        ]
+    );
+
+    let input = r#"
+    main:
+        sub missing, 1
+    "#
+    .to_string();
+
+    let error = compile(input).unwrap_err();
+    assert_eq!(
+        error.diagnostics()[0].message,
+        "Undefined variable 'missing'"
+    );
+}
+
+#[test]
+fn test_compile_branches() {
+    let input = r#"
+    main:
+        var i = 0
+    .loop:
+        mov forward, 1
+        add i, 1
+        blt i, 4, .loop
+        bne i, 4, .done
+        beq i, 5, .done
+        bge i, 10, .done
+        j .loop
+    .done:
+    "#
+    .to_string();
+
+    let output = compile_to_arduino(input.clone()).unwrap();
+    assert!(output.contains(
+        r#"void main_loop() {
+    long var_i = 0;
+    var_i = 0;
+label_loop:;
+    forward(1);
+    var_i = wrapping_add(var_i, 1);
+    if (var_i < 4) goto label_loop;
+    if (var_i != 4) goto label_done;
+    if (var_i == 5) goto label_done;
+    if (var_i >= 10) goto label_done;
+    goto label_loop;
+label_done:;
+}"#
+    ));
+
+    let ir = compile(input).unwrap();
+    assert!(ir.contains(
+        r#""Branch": {
+            "condition": "less",
+            "left": "i",
+            "right": 4,
+            "label": "loop"
+          }"#
+    ));
+    assert!(ir.contains(
+        r#""Goto": {
+            "label": "loop"
+          }"#
+    ));
+}
+
+#[test]
+fn test_compile_undefined_label() {
+    let input = r#"
+    circle:
+    .top:
+        j .top
+
+    main:
+    .again:
+    .again:
+        j .top
+    "#
+    .to_string();
+
+    let error = compile(input).unwrap_err();
+    let messages: Vec<&str> = error
+        .diagnostics()
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Label '.again' is already defined at 7:5",
+            "Undefined label '.top'"
+        ]
+    );
+}
+
+#[test]
+fn test_compile_repeat() {
+    let input = r#"
+    var sides = 4
+
+    square:
+        repeat sides:
+            mov forward, 2
+            repeat 2:
+                var turn = 1
+                mov direction, turn
+            end
+        end
+
+    main:
+        jal square
+    "#
+    .to_string();
+
+    let output = compile_to_arduino(input.clone()).unwrap();
+    assert!(output.contains(
+        r#"void section_square() {
+    long var_turn = 0;
+    for (long repeat_1 = var_sides; repeat_1 > 0; repeat_1--) {
+        forward(2);
+        for (long repeat_2 = 2; repeat_2 > 0; repeat_2--) {
+            var_turn = 1;
+            steer(var_turn);
+        }
+    }
+}"#
+    ));
+
+    let ir = compile(input).unwrap();
+    assert!(ir.contains(
+        r#""Repeat": {
+            "count": "sides",
+            "body": ["#
+    ));
+}
+
+#[test]
+fn test_compile_repeat_errors() {
+    let input = r#"
+    main:
+        repeat 3:
+        .inside:
+            mov forward, 1
+        end
+        end
+        repeat 2:
+            mov forward, 1
+    circle:
+        mov forward, 1
+    "#
+    .to_string();
+
+    let error = compile(input).unwrap_err();
+    let messages: Vec<(usize, &str)> = error
+        .diagnostics()
+        .iter()
+        .map(|d| (d.span.line, d.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (4, "Labels are not allowed inside a repeat block"),
+            (7, "'end' without a matching 'repeat'"),
+            (10, "Expected 'end' to close repeat"),
+        ]
+    );
+}
+
+#[test]
+fn test_compile_semantic_errors() {
+    let input = r#"
+    circle:
+        jal typo
+        mov forward, 1
+
+    circle:
+        mov forward, 2
+
+    forward:
+        repeat 2:
+            jal missing
+        end
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input).unwrap_err();
+    let messages: Vec<(usize, &str)> = error
+        .diagnostics()
+        .iter()
+        .map(|d| (d.span.line, d.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (1, "Program has no 'main' section"),
+            (3, "Undefined section 'typo'"),
+            (6, "Section 'circle' is already defined at 2:5"),
+            (11, "Undefined section 'missing'"),
+        ]
+    );
+}
+
+#[test]
+fn test_section_names_clashing_with_cpp() {
+    // Section functions are prefixed, so helpers, Arduino core names, C++
+    // keywords and the generated variable and counter names are all free
+    let names = [
+        "forward", "setup", "loop", "for", "int", "HIGH", "repeat_1", "var_x", "label_x", "millis",
+        "yield", "abs", "min", "Serial",
+    ];
+    for name in names {
+        let input = format!(
+            "{}:\n    ret\n\nmain:\n    var x = 1\n    jal {}\n",
+            name, name
+        );
+        let output = compile_to_arduino(input).unwrap();
+        assert!(output.contains(&format!("void section_{}() {{\n", name)));
+        assert!(output.contains(&format!("    section_{}();\n", name)));
+    }
+}
+
+#[test]
+fn test_compile_ir_without_main() {
+    let input = r#"
+    circle:
+        mov forward, 1
+    "#
+    .to_string();
+
+    assert!(compile(input.clone()).is_ok());
+
+    let error = compile_to_arduino(input).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Semantic error at 1:1: Program has no 'main' section"
+    );
+}
+
+#[test]
+fn test_compile_unbounded_recursion() {
+    let input = r#"
+    spiral:
+        mov forward, 1
+        jal turn
+
+    turn:
+        mov direction, 1
+        jal spiral
+
+    main:
+        jal main
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input).unwrap_err();
+    let messages: Vec<(usize, &str)> = error
+        .diagnostics()
+        .iter()
+        .map(|d| (d.span.line, d.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (
+                4,
+                "Sections 'spiral', 'turn' call each other with no way to stop, which overflows the robot's stack"
+            ),
+            (
+                11,
+                "Section 'main' calls itself with no way to stop, which overflows the robot's stack"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_check_reports_warnings() {
+    let input = r#"
+    var n = 3
+
+    countdown:
+        sub n, 1
+        beq n, 0, .done
+        jal countdown
+    .done:
+
+    main:
+        jal countdown
+    "#
+    .to_string();
+
+    // Recursion guarded by a branch compiles, with a warning from check()
+    assert!(compile_to_arduino(input.clone()).is_ok());
+
+    let diagnostics = check(input);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].severity, Severity::Warning);
+    assert_eq!(diagnostics[0].span.line, 7);
+    assert!(diagnostics[0]
+        .message
+        .starts_with("Section 'countdown' calls itself; make sure the recursion is bounded"));
+}
+
+#[test]
+fn test_check_deep_call_chain() {
+    let mut input = String::from("s0:\n    mov forward, 1\n");
+    for i in 1..=MAX_CALL_DEPTH {
+        input.push_str(&format!("s{}:\n    jal s{}\n", i, i - 1));
+    }
+    input.push_str(&format!("main:\n    jal s{}\n", MAX_CALL_DEPTH));
+
+    let diagnostics = check(input);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].severity, Severity::Warning);
+    assert_eq!(
+        diagnostics[0].message,
+        format!(
+            "Calls from 'main' nest {} sections deep, more than the recommended {}",
+            MAX_CALL_DEPTH + 2,
+            MAX_CALL_DEPTH
+        )
+    );
+}
+
+#[test]
+fn test_compile_invalid_movement() {
+    let input = r#"
+    main:
+        mov sideways, 3
+        mov direction, 3
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input.clone()).unwrap_err();
+    let diagnostics = error.diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].stage, Stage::Parser);
+    assert_eq!(diagnostics[0].span, Span::new(23, 31, 3, 13));
+    assert_eq!(
+        diagnostics[0].message,
+        "Unknown movement 'sideways', expected one of: forward, backward, direction, wait, speed, left, right"
+    );
+
+    let input = input.replace("mov sideways, 3", "mov forward, 3");
+    let error = compile_to_arduino(input).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Semantic error at 4:9: Invalid direction 3, expected 0 (straight), 1 (left) or 2 (right)"
+    );
+}
+
+#[test]
+fn test_ir_schema_is_up_to_date() {
+    // Regenerate with `UPDATE_SCHEMA=1 cargo test test_ir_schema_is_up_to_date`
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/ir.schema.json");
+    let schema = ir_schema() + "\n";
+    if std::env::var_os("UPDATE_SCHEMA").is_some() {
+        std::fs::write(path, &schema).unwrap();
+    }
+    let committed = std::fs::read_to_string(path).unwrap_or_default();
+    assert!(
+        committed == schema,
+        "ir.schema.json is out of date, regenerate it with UPDATE_SCHEMA=1"
+    );
+}
+
+#[test]
+fn test_compile_ir_to_arduino() {
+    let input = r#"
+    var sides = 4
+
+    main:
+        repeat sides:
+            mov forward, 2
+            mov direction, 1
+        end
+    "#
+    .to_string();
+
+    let ir = compile(input.clone()).unwrap();
+    assert_eq!(
+        compile_ir_to_arduino(ir).unwrap(),
+        compile_to_arduino(input).unwrap()
+    );
+}
+
+#[test]
+fn test_compile_ir_without_version() {
+    // IR saved before the version field existed
+    let ir = r#"{
+  "sections": [
+    {
+      "name": "main",
+      "commands": [
+        { "Move": { "type": "forward", "amount": 4 } }
+      ]
+    }
+  ]
+}"#
+    .to_string();
+
+    let output = compile_ir_to_arduino(ir).unwrap();
+    assert!(output.contains("void main_loop() {\n    forward(4);\n}"));
+}
+
+#[test]
+fn test_compile_invalid_ir() {
+    let ir = format!(r#"{{ "version": {}, "sections": [] }}"#, IR_VERSION + 1);
+    let error = compile_ir_to_arduino(ir).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "IR error at 0:0: Unsupported IR version {}, this compiler reads versions 1 to {}",
+            IR_VERSION + 1,
+            IR_VERSION
+        )
+    );
+
+    let error = compile_ir_to_arduino(
+        r#"{ "sections": [{ "name": "main", "commands": [{ "Move": { "type": "sideways", "amount": 1 } }] }] }"#
+            .to_string(),
+    )
+    .unwrap_err();
+    let diagnostic = &error.diagnostics()[0];
+    assert_eq!(diagnostic.stage, Stage::Ir);
+    assert!(diagnostic.message.contains("unknown variant `sideways`"));
+
+    let error = compile_ir_to_arduino(
+        r#"{ "sections": [{ "name": "main", "commands": [{ "Jump": { "label": "nowhere" } }] }] }"#
+            .to_string(),
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.diagnostics()[0].message,
+        "Undefined section 'nowhere'"
+    );
+}
+
+#[test]
+fn test_ir_names_must_be_identifiers() {
+    let evil = "x(); } void evil() { for(;;)";
+    let ir = serde_json::json!({
+        "globals": [{ "name": "g-1", "value": 0 }],
+        "sections": [
+            { "name": evil, "commands": [] },
+            { "name": "main", "commands": [
+                { "Jump": { "label": evil } },
+                { "Repeat": { "count": "n; evil()", "body": [
+                    { "Label": { "name": "a b" } },
+                    { "Goto": { "label": "" } },
+                    { "Add": { "variable": "1x", "value": 1 } }
+                ] } }
+            ] }
+        ]
+    });
+
+    let error = compile_ir_to_arduino(ir.to_string()).unwrap_err();
+    let messages: Vec<&str> = error
+        .diagnostics()
+        .iter()
+        .map(|diagnostic| diagnostic.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            "Invalid variable name \"g-1\"",
+            "Invalid section name \"x(); } void evil() { for(;;)\"",
+            "Invalid section name \"x(); } void evil() { for(;;)\"",
+            "Invalid variable name \"n; evil()\"",
+            "Invalid label name \"a b\"",
+            "Invalid label name \"\"",
+            "Invalid variable name \"1x\"",
+        ]
+    );
+    assert!(error
+        .diagnostics()
+        .iter()
+        .all(|diagnostic| diagnostic.stage == Stage::Ir));
+
+    let options = CodegenOptions {
+        non_blocking: true,
+        ..CodegenOptions::default()
+    };
+    assert!(compile_ir_to_with("arduino", ir.to_string(), &options).is_err());
+
+    // Names the parser produces still round-trip
+    let ir = compile("_g:\n    ret\nmain:\n    var n_2 = 1\n    jal _g\n".to_string()).unwrap();
+    assert!(compile_ir_to_arduino(ir).is_ok());
+}
+
+#[test]
+fn test_compile_to_target() {
+    let input = r#"
+    main:
+        mov forward, 2
+    "#
+    .to_string();
+
+    let arduino = compile_to_arduino(input.clone()).unwrap();
+    assert_eq!(compile_to("arduino", input.clone()).unwrap(), arduino);
+
+    let error = compile_to("z80", input).unwrap_err();
+    assert!(matches!(error, CompilerError::UnknownTarget(ref name) if name == "z80"));
+    assert_eq!(
+        error.to_string(),
+        "Unknown target 'z80', expected one of: arduino"
+    );
+}
+
+#[test]
+fn test_compile_with_robot_profile() {
+    let input = r#"
+    main:
+        mov direction, 1
+        mov forward, 2
+    "#
+    .to_string();
+
+    let profile = RobotProfile::from_toml(
+        r#"
+        [drive]
+        enable = 11
+        input1 = 7
+        input2 = 8
+        duty = 200
+        inverted = true
+
+        [timing]
+        settle_ms = 250
+        "#,
+    )
+    .unwrap();
+    let options = CodegenOptions {
+        profile,
+        ..CodegenOptions::default()
+    };
+    let output = compile_to_with("arduino", input.clone(), &options).unwrap();
+
+    assert!(output.contains("int enA = 11;\nint in1 = 8;\nint in2 = 7;"));
+    assert!(output.contains("int enB = 10;\nint in3 = 5;\nint in4 = 6;"));
+    assert!(output.contains("analogWrite(enA, 200);"));
+    assert!(output.contains("analogWrite(enB, 255);"));
+    assert!(output.contains("delay(250);"));
+    assert!(!output.contains("delay(500);\n\tdigitalWrite(in2, HIGH);"));
+
+    // The default profile is the original hard-coded chassis
+    assert_eq!(
+        compile_to_with("arduino", input.clone(), &CodegenOptions::default()).unwrap(),
+        compile_to_arduino(input).unwrap()
+    );
+}
+
+#[test]
+fn test_non_blocking() {
+    let input = r#"
+    turn:
+        var n = 0
+        mov left, 90deg
+        add n, 1
+        ret
+    main:
+        repeat 2:
+            mov forward, 1
+        end
+        jal turn
+    "#
+    .to_string();
+
+    let options = CodegenOptions {
+        non_blocking: true,
+        ..CodegenOptions::default()
+    };
+    let output = compile_to_with("arduino", input, &options).unwrap();
+
+    // Nothing in the sketch waits with delay()
+    assert!(!output.contains("delay("));
+    assert!(output.contains("if (halted || millis() - wait_start < wait_ms) {"));
+    assert!(output.contains("const int STACK_SIZE = 2;"));
+    assert!(output.contains("long locals[1];"));
+
+    // A forward move settles, drives and settles again, one case each
+    assert!(output.contains(
+        "    case 2:\n        // line 9\n        wait_for(500);\n        return;\n    \
+         case 3:\n        drive_forward();\n        wait_for(1000);\n        return;\n    \
+         case 4:\n        drive_stop();\n        wait_for(500);\n        return;\n"
+    ));
+    // The repeat jumps back to its counter check and exits past the body
+    assert!(output.contains("if (frame.locals[0] <= 0) frame.step = 6;"));
+    assert!(output.contains("        frame.step = 1;\n"));
+    assert!(output.contains("        call(step_turn);\n"));
+
+    // Locals live in the frame, so every call gets its own
+    assert!(output.contains("frame.locals[0] = wrapping_add(frame.locals[0], 1);"));
+    // 90deg at the default 45 degrees per second, stretched for the speed
+    assert!(output.contains("drive_forward();\n        wait_for(at_speed(2000));"));
+    assert!(output.contains("\tcurrent_speed = percent;\n"));
+}
+
+#[test]
+fn test_non_blocking_unchecked_program_is_an_error() {
+    // The parser alone does not check labels, so generate has to
+    let program = Parser::new("main:\n    j .nowhere\n".to_string())
+        .parse()
+        .unwrap();
+    let backend = StateMachineBackend::default();
+    assert_eq!(
+        generate(&backend, &program),
+        Err("Undefined label '.nowhere' in section 'main'".to_string())
+    );
+}
+
+#[test]
+fn test_non_blocking_recursion_uses_max_call_depth() {
+    let input = r#"
+    var n = 3
+    spin:
+        mov wait, 1
+        sub n, 1
+        bge n, 1, .again
+        ret
+    .again:
+        jal spin
+    main:
+        jal spin
+    "#
+    .to_string();
+
+    let options = CodegenOptions {
+        non_blocking: true,
+        ..CodegenOptions::default()
+    };
+    let output = compile_to_with("arduino", input, &options).unwrap();
+    assert!(output.contains(&format!("const int STACK_SIZE = {};", MAX_CALL_DEPTH)));
+    assert!(output.contains("        var_n = wrapping_sub(var_n, 1);\n"));
+}
+
+#[test]
+fn test_default_profile_file_matches_default() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/profiles/default.toml");
+    assert_eq!(RobotProfile::load(path).unwrap(), RobotProfile::default());
+}
+
+#[test]
+fn test_speed() {
+    let input = r#"
+    var slow = 30
+
+    main:
+        mov speed, 50
+        mov forward, 2
+        mov speed, slow
+    "#
+    .to_string();
+
+    let ir = compile(input.clone()).unwrap();
+    assert!(ir.contains(r#""type": "speed""#));
+
+    let output = compile_to_arduino(input).unwrap();
+    assert!(output.contains("    speed(50);\n"));
+    assert!(output.contains("    speed(var_slow);\n"));
+    assert!(output.contains("void speed(long percent){"));
+    assert!(output.contains("analogWrite(enA, percent * 255 / 100);"));
+
+    let input = r#"
+    main:
+        mov speed, 101
+        mov speed, 255
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Semantic error at 3:9: Invalid speed 101, expected a percentage from 0 to 100\n\
+         Semantic error at 4:9: Invalid speed 255, expected a percentage from 0 to 100"
+    );
+}
+
+#[test]
+fn test_unit_moves() {
+    let input = r#"
+    main:
+        mov forward, 30cm
+        mov backward, 10cm
+        mov left, 90deg
+        mov right, 2
+    "#
+    .to_string();
+
+    let ir = compile(input.clone()).unwrap();
+    assert!(ir.contains(
+        r#""amount": 30,
+            "unit": "cm""#
+    ));
+
+    let output = compile_to_arduino(input.clone()).unwrap();
+    assert!(output.contains("    forward_ms(at_speed(1500)); // 30cm\n"));
+    assert!(output.contains("    backwards_ms(at_speed(500)); // 10cm\n"));
+    assert!(output.contains("    turn_left_ms(at_speed(2000)); // 90deg\n"));
+    assert!(output.contains("    turn_right_ms(2000);\n"));
+    // Calibration is at full power, so the sketch drives longer when slower
+    assert!(output.contains("\tcurrent_speed = percent;\n"));
+    assert!(output.contains("\treturn time * 100 / current_speed;\n"));
+
+    // The profile's calibration decides how long a distance takes
+    let profile = RobotProfile::from_toml("[calibration]\ncm_per_second = 40.0").unwrap();
+    let output = compile_to_with(
+        "arduino",
+        input,
+        &CodegenOptions {
+            profile,
+            ..CodegenOptions::default()
+        },
+    )
+    .unwrap();
+    assert!(output.contains("    forward_ms(at_speed(750)); // 30cm\n"));
+
+    // The simulator covers the same distance at half speed, in twice the time
+    let simulation = simulate(
+        "main:\n    mov speed, 50\n    mov forward, 30cm\n".to_string(),
+        &SimulationOptions::default(),
+    )
+    .unwrap();
+    let end = simulation.trace.last().unwrap();
+    assert!((end.pose.x - 30.0).abs() < 1e-9);
+    assert_eq!(simulation.duration_ms, 500 + 3000 + 500);
+
+    let input = r#"
+    main:
+        mov forward, 90deg
+        mov wait, 5cm
+        var d = 3cm
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Parser error at 5:17: Unit 'cm' is only allowed on a mov amount"
+    );
+
+    let input = r#"
+    main:
+        mov forward, 90deg
+        mov wait, 5cm
+        mov direction, 1deg
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Semantic error at 3:9: 'mov forward' takes a time or cm, not 'deg'\n\
+         Semantic error at 4:9: 'mov wait' takes a time, not 'cm'\n\
+         Semantic error at 5:9: 'mov direction' does not take a unit, found 'deg'"
+    );
+}
+
+#[test]
+fn test_fractional_and_millisecond_times() {
+    let input = r#"
+    main:
+        mov wait, 0.5
+        mov forward, 1.25s
+        mov backward, 250ms
+        mov right, 2s
+        mov wait, 3
+    "#
+    .to_string();
+
+    // Times are stored in whole milliseconds
+    let ir = compile(input.clone()).unwrap();
+    assert!(ir.contains(
+        r#""amount": 500,
+            "unit": "ms""#
+    ));
+    assert!(ir.contains(
+        r#""amount": 2000,
+            "unit": "ms""#
+    ));
+
+    let output = compile_to_arduino(input).unwrap();
+    assert!(output.contains(
+        "void main_loop() {\n    wait_ms(500);\n    forward_ms(1250);\n    backwards_ms(250);\n    \
+         turn_right_ms(2000);\n    wait(3);\n}"
+    ));
+
+    let input = r#"
+    var t = 0.5
+    main:
+        mov speed, 50ms
+        mov wait, 0.0001
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Parser error at 2:13: Times like '0.5' or '500ms' are only allowed as a mov amount\n\
+         Lexer error at 5:19: '0.0001' is more precise than a millisecond"
+    );
+}
+
+#[test]
+fn test_integer_literals() {
+    let input = r#"
+    var offset = -5
+    var mask = 0xFF
+    var flags = 0b1010
+    var distance = 1_000
+
+    main:
+        add offset, -0x10
+        blt offset, -20, .done
+        mov speed, 0x32
+    .done:
+        ret
+    "#
+    .to_string();
+
+    let ir = compile(input.clone()).unwrap();
+    assert!(ir.contains(r#""value": -5"#));
+    assert!(ir.contains(r#""value": 255"#));
+    assert!(ir.contains(r#""value": 10"#));
+    assert!(ir.contains(r#""value": 1000"#));
+
+    let output = compile_to_arduino(input).unwrap();
+    assert!(output.contains("long var_offset = -5;\n"));
+    assert!(output.contains("var_offset = wrapping_add(var_offset, -16);"));
+    assert!(output.contains("if (var_offset < -20) goto label_done;"));
+    assert!(output.contains("speed(50);"));
+
+    let input = r#"
+    main:
+        mov forward, -3
+        mov wait, -0.5
+        mov backward, -10cm
+        mov direction, -1
+        mov forward, 0x1_0000_0000
+    "#
+    .to_string();
+
+    let error = compile_to_arduino(input.clone()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Lexer error at 7:22: Integer literal '0x1_0000_0000' is out of range"
+    );
+
+    let input = input.replace("0x1_0000_0000", "0b11");
+    let error = compile_to_arduino(input).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Semantic error at 3:9: 'mov forward' cannot take a negative amount, found -3\n\
+         Semantic error at 4:9: 'mov wait' cannot take a negative amount, found -500ms\n\
+         Semantic error at 5:9: 'mov backward' cannot take a negative amount, found -10cm\n\
+         Semantic error at 6:9: Invalid direction -1, expected 0 (straight), 1 (left) or 2 (right)"
+    );
+}
+
+#[test]
+fn test_move_durations_fit_an_unsigned_long() {
+    // Delays are unsigned long milliseconds, which hold 4294967 seconds
+    let error = compile_to_arduino("main:\n    mov wait, 4294968\n".to_string()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Semantic error at 2:5: 'mov wait' cannot take more than 4294967 seconds, found 4294968"
+    );
+    let output = compile_to_arduino("main:\n    mov forward, 4294967\n".to_string()).unwrap();
+    assert!(output.contains("    forward(4294967);\n"));
+
+    // Distances depend on the calibration, so they are caught generating
+    let error =
+        compile_to_arduino("main:\n    mov forward, 2000000000cm\n".to_string()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Codegen error: '2000000000cm' is 100000000000 ms, which does not fit in an unsigned long"
+    );
+
+    // Negative variable amounts move for no time, in both sketches
+    let input = "main:\n    var t = -1\n    mov forward, t\n    mov left, t\n".to_string();
+    let output = compile_to_arduino(input.clone()).unwrap();
+    assert!(output.contains("    forward(var_t);\n    turn_left_ms(seconds_ms(var_t));\n"));
+    assert!(output.contains("\tforward_ms(seconds_ms(time));\n"));
+    assert!(output
+        .contains("unsigned long seconds_ms(long time){\n\tif (time < 0) {\n\t\treturn 0;\n\t}\n"));
+    let options = CodegenOptions {
+        non_blocking: true,
+        ..CodegenOptions::default()
+    };
+    let output = compile_to_with("arduino", input, &options).unwrap();
+    assert!(output.contains("wait_for(seconds_ms(frame.locals[0]));"));
+}
+
+#[test]
+fn test_negative_variable_amounts_match_the_sketch() {
+    let input = "main:\n    var t = -1\n    mov forward, t\n    mov wait, t\n".to_string();
+
+    // The blocking helpers turn the amount into no time at all...
+    let output = compile_to_arduino(input.clone()).unwrap();
+    assert!(output.contains("    forward(var_t);\n    wait(var_t);\n"));
+    assert!(output.contains("\tforward_ms(seconds_ms(time));\n"));
+    assert!(output.contains("\twait_ms(seconds_ms(time));\n"));
+    assert!(output.contains("\tif (time < 0) {\n\t\treturn 0;\n\t}\n"));
+
+    // ...and so does the simulator: only the settles around the drive
+    let simulation = simulate(input, &SimulationOptions::default()).unwrap();
+    assert_eq!(simulation.duration_ms, 500 + 500);
+    let last = simulation.trace.last().unwrap();
+    assert_eq!((last.pose.x, last.pose.y), (0.0, 0.0));
+}
+
+#[test]
+fn test_simulate() {
+    let input = r#"
+    var sides = 0
+    side:
+        mov forward, 50cm
+        mov right, 90deg
+        add sides, 1
+    main:
+        repeat 4:
+            jal side
+        end
+    "#
+    .to_string();
+
+    let simulation = simulate(input, &SimulationOptions::default()).unwrap();
+    assert_eq!(simulation.stop, StopReason::Finished);
+
+    // Four sides, each 2.5 s of driving plus a 2 s turn, and their delays
+    let side_ms = 500 + 2500 + 500 + (500 + 500 + 500) + (500 + 2000 + 500) + 500;
+    assert_eq!(simulation.duration_ms, 4 * side_ms);
+
+    // Right turns swing the car clockwise back to where it started facing
+    let last = simulation.trace.last().unwrap();
+    assert!(last.pose.heading.abs() < 1e-6 || (last.pose.heading - 360.0).abs() < 1e-6);
+    assert_eq!(
+        (last.drive, last.steering),
+        (Drive::Stopped, Steering::Straight)
+    );
+    assert!(simulation
+        .trace
+        .iter()
+        .any(|keyframe| keyframe.section == "side" && keyframe.line == 5));
+    assert!(simulation
+        .trace
+        .windows(2)
+        .all(|pair| pair[0].time_ms < pair[1].time_ms));
+
+    // Recursion stops the robot once the sketch would run out of frames
+    let input = r#"
+    var n = 100
+    down:
+        sub n, 1
+        blt n, 1, .done
+        jal down
+    .done:
+    main:
+        jal down
+    "#
+    .to_string();
+    let simulation = simulate(input.clone(), &SimulationOptions::default()).unwrap();
+    assert_eq!(simulation.stop, StopReason::StackOverflow);
+
+    let input = input.replace("var n = 100", "var n = 3");
+    let simulation = simulate(input, &SimulationOptions::default()).unwrap();
+    assert_eq!(simulation.stop, StopReason::Finished);
+    assert_eq!(simulation.duration_ms, 0);
+
+    // Only checked programs run
+    let error = simulate(
+        "main:\n    jal nowhere\n".to_string(),
+        &SimulationOptions::default(),
+    )
+    .unwrap_err();
+    assert_eq!(error.diagnostics()[0].stage, Stage::Semantic);
+}
+
+#[test]
+fn test_render_svg() {
+    let input = r#"
+    square:
+        repeat 4:
+            mov forward, 40cm
+            mov left, 90deg
+        end
+    main:
+        jal square
+    "#
+    .to_string();
+
+    let simulation = simulate(input, &SimulationOptions::default()).unwrap();
+    let svg = render_svg(
+        &simulation,
+        &RenderOptions {
+            width: 400,
+            height: 400,
+            ..RenderOptions::default()
+        },
+    );
+
+    assert!(svg.contains(r#"width="400" height="400" viewBox="0 0 400 400""#));
+    assert!(svg.trim_end().ends_with("</svg>"));
+    assert_eq!(svg.matches("class=\"turn\"").count(), 4);
+    assert!(svg.contains("<title>left turn, square line 5</title>"));
+    assert!(svg.contains("class=\"start\"") && svg.contains("class=\"end\""));
+}
+
+#[test]
+fn test_arena_collisions_and_goals() {
+    let arena = Arena::from_toml(
+        r#"
+        name = "wall ahead"
+
+        [start]
+        x = 0.0
+        y = 50.0
+
+        [[walls]]
+        from = [100.0, 0.0]
+        to = [100.0, 200.0]
+
+        [[boxes]]
+        x = -60.0
+        y = 40.0
+        width = 20.0
+        height = 20.0
+
+        [[goals]]
+        name = "halfway"
+        x = 40.0
+        y = 0.0
+        width = 20.0
+        height = 100.0
+        "#,
+    )
+    .unwrap();
+    let options = SimulationOptions {
+        arena: Some(arena.clone()),
+        ..SimulationOptions::default()
+    };
+
+    // 10 s at 20 cm/s would reach x = 200, but the wall is at 100
+    let input = "main:\n    mov forward, 10\n    mov backward, 3s\n".to_string();
+    let simulation = simulate(input, &options).unwrap();
+
+    // The car reaches x = 40 two seconds into the drive, after the 500 ms settle
+    assert_eq!(simulation.goal_reached_ms("halfway"), Some(2500));
+    assert_eq!(simulation.goal_reached_ms("elsewhere"), None);
+
+    // The 10 cm radius touches the wall once the center passes x = 90
+    assert_eq!(simulation.collisions.len(), 1);
+    let collision = &simulation.collisions[0];
+    assert_eq!(collision.obstacle, "wall 1");
+    assert_eq!(simulation.first_collision_ms(), Some(collision.time_ms));
+    assert!((4990..=5010).contains(&collision.time_ms));
+    assert!(collision.x < 90.0 && collision.x > 89.0);
+
+    // Blocked for the rest of the drive, then it backs up 60 cm
+    let last = simulation.trace.last().unwrap();
+    assert!((last.pose.x - (collision.x - 60.0)).abs() < 1e-6);
+    assert_eq!(last.pose.y, 50.0);
+
+    // Without an arena nothing is reported and the JSON has no extra fields
+    let simulation = simulate(
+        "main:\n    mov forward, 10\n".to_string(),
+        &SimulationOptions::default(),
+    )
+    .unwrap();
+    assert!(simulation.collisions.is_empty());
+    let json = serde_json::to_string(&simulation).unwrap();
+    assert!(!json.contains("collisions") && !json.contains("goals"));
+
+    // The arena is drawn under the path, with the collision marked
+    let input = "main:\n    mov backward, 4\n".to_string();
+    let simulation = simulate(input, &options).unwrap();
+    assert_eq!(simulation.collisions[0].obstacle, "box 1");
+    let svg = render_svg(
+        &simulation,
+        &RenderOptions {
+            arena: Some(arena),
+            ..RenderOptions::default()
+        },
+    );
+    assert_eq!(svg.matches("class=\"wall\"").count(), 1);
+    assert!(svg.contains("class=\"box\""));
+    assert!(svg.contains(">halfway</text>"));
+    assert!(svg.contains("<title>Hit box 1 at "));
+}
+
+#[test]
+fn test_render_escapes_arena_names() {
+    let arena = Arena::from_toml(
+        r#"
+        name = "escaping"
+
+        [[goals]]
+        name = "A & B <\"C\">"
+        x = 40.0
+        y = -10.0
+        width = 20.0
+        height = 20.0
+        "#,
+    )
+    .unwrap();
+    let simulation = simulate(
+        "main:\n    mov forward, 2\n".to_string(),
+        &SimulationOptions {
+            arena: Some(arena.clone()),
+            ..SimulationOptions::default()
+        },
+    )
+    .unwrap();
+    let options = RenderOptions {
+        arena: Some(arena),
+        ..RenderOptions::default()
+    };
+
+    let svg = render_svg(&simulation, &options);
+    assert!(svg.contains(">A &amp; B &lt;&quot;C&quot;&gt;</text>"));
+    assert!(!svg.contains("A & B"));
+
+    // The SVG stays well formed, so it still converts to PNG
+    #[cfg(feature = "png")]
+    {
+        let png = compiler::render_png(&simulation, &options).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+}
+
+#[test]
+fn test_example_arena_loads() {
+    let arena = Arena::load(concat!(env!("CARGO_MANIFEST_DIR"), "/arenas/corridor.toml")).unwrap();
+    assert_eq!(arena.name, "corridor");
+
+    // Driving straight down the corridor runs into the box
+    let options = SimulationOptions {
+        arena: Some(arena),
+        ..SimulationOptions::default()
+    };
+    let simulation = simulate("main:\n    mov forward, 300cm\n".to_string(), &options).unwrap();
+    assert_eq!(simulation.collisions[0].obstacle, "box 1");
+    assert_eq!(simulation.goal_reached_ms("exit"), None);
+}
+
+#[test]
+fn test_debugger_protocol() {
+    let input = "main:\n    var i = 0\n    repeat 3:\n        add i, 2\n    end\n    mov wait, 1\n";
+    let mut debugger = debug(input.to_string(), &SimulationOptions::default()).unwrap();
+    let entry = serde_json::to_value(debugger.entry()).unwrap();
+    assert_eq!(entry["event"], "stopped");
+    assert_eq!(entry["reason"], "entry");
+    assert_eq!(entry["frame"]["line"], 2);
+
+    let mut send = |message: &str| {
+        let request: DebugRequest = serde_json::from_str(message).unwrap();
+        serde_json::to_value(debugger.handle(request)).unwrap()
+    };
+    let breakpoints = send(r#"{ "command": "set_breakpoints", "breakpoints": [{ "line": 4 }] }"#);
+    assert_eq!(breakpoints["verified"], serde_json::json!([true]));
+
+    // The loop body is hit once per iteration
+    for i in 0..3 {
+        let stopped = send(r#"{ "command": "continue" }"#);
+        assert_eq!(stopped["reason"], "breakpoint");
+        assert_eq!(stopped["frame"]["line"], 4);
+        let variables = send(r#"{ "command": "variables" }"#);
+        assert_eq!(
+            variables["locals"],
+            serde_json::json!([{ "name": "i", "value": 2 * i }])
+        );
+    }
+
+    let frames = send(r#"{ "command": "stack_trace" }"#);
+    assert_eq!(
+        frames["frames"],
+        serde_json::json!([{ "section": "main", "line": 4 }])
+    );
+    let error = send(r#"{ "command": "variables", "frame": 1 }"#);
+    assert_eq!(error["event"], "error");
+
+    let terminated = send(r#"{ "command": "continue" }"#);
+    assert_eq!(terminated["event"], "terminated");
+    assert_eq!(terminated["simulation"]["stop"], "finished");
+
+    assert!(matches!(
+        debug(
+            "main:\n    jal nowhere\n".to_string(),
+            &SimulationOptions::default()
+        ),
+        Err(CompilerError::Diagnostics(_))
+    ));
+}
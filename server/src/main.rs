@@ -1,15 +1,76 @@
 use actix_cors::Cors;
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use compiler::{compile, compile_to_arduino, CompilerError};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_ws::AggregatedMessage;
+use compiler::{
+    backend, compile, compile_ir_to_with, compile_to_with, debug, ir_schema, render_png,
+    render_svg, simulate, Arena, CodegenOptions, CompilerError, DebugEvent, DebugRequest, Debugger,
+    Diagnostic, Pose, RenderOptions, RobotProfile, Simulation, SimulationOptions, TARGETS,
+};
 use serde::{Deserialize, Serialize};
 use std::env;
-use std::path::Path;
 use std::process::Command;
 use tempfile::tempdir;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct CompileRequest {
     code: String,
+    /// Robot the code is generated for; the default chassis when omitted
+    #[serde(default)]
+    profile: Option<RobotProfile>,
+    /// Generate a millis()-based state machine instead of blocking code
+    #[serde(default)]
+    non_blocking: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CompileIrRequest {
+    /// The JSON IR, as returned in `output` by `/api/compile`
+    ir: String,
+    /// Robot the code is generated for; the default chassis when omitted
+    #[serde(default)]
+    profile: Option<RobotProfile>,
+    /// Generate a millis()-based state machine instead of blocking code
+    #[serde(default)]
+    non_blocking: bool,
+}
+
+/// Longest robot time a simulation may cover, so a `main` that loops
+/// forever cannot hang a worker.
+const MAX_SIMULATED_MS: u64 = 10 * 60 * 1000;
+/// Most instructions a simulation may run, for loops that never move.
+const MAX_SIMULATED_STEPS: usize = 1_000_000;
+/// Shortest time between keyframes while the car moves.
+const MIN_SAMPLE_MS: u64 = 20;
+/// Most keyframes a trace may hold. Every move adds some whatever the
+/// sample time, so this is what bounds the size of the response.
+const MAX_KEYFRAMES: usize = 100_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SimulateRequest {
+    code: String,
+    #[serde(default)]
+    profile: Option<RobotProfile>,
+    #[serde(default)]
+    start: Pose,
+    /// Obstacles and goals to report collisions with and arrivals at; its
+    /// start pose replaces `start`
+    #[serde(default)]
+    arena: Option<Arena>,
+    /// Restart `main` when it returns, like the robot does
+    #[serde(default)]
+    loop_main: bool,
+    /// Capped at `MAX_SIMULATED_MS`
+    #[serde(default)]
+    max_duration_ms: Option<u64>,
+    /// Capped at `MAX_SIMULATED_STEPS`
+    #[serde(default)]
+    max_steps: Option<usize>,
+    /// At least `MIN_SAMPLE_MS`
+    #[serde(default)]
+    sample_ms: Option<u64>,
+    /// Capped at `MAX_KEYFRAMES`
+    #[serde(default)]
+    max_keyframes: Option<usize>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,6 +81,17 @@ struct CompileResponse {
 #[derive(Debug, Serialize, Deserialize)]
 struct ErrorResponse {
     error: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl From<CompilerError> for ErrorResponse {
+    fn from(e: CompilerError) -> Self {
+        ErrorResponse {
+            error: e.to_string(),
+            diagnostics: e.diagnostics().to_vec(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,21 +103,287 @@ struct UploadRequest {
 async fn compile_ir(req: web::Json<CompileRequest>) -> impl Responder {
     match compile(req.code.clone()) {
         Ok(output) => HttpResponse::Ok().json(CompileResponse { output }),
-        Err(e) => HttpResponse::BadRequest().json(ErrorResponse {
+        Err(e) => HttpResponse::BadRequest().json(ErrorResponse::from(e)),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TargetInfo {
+    name: String,
+    file_extension: String,
+}
+
+/// Compiles the code with the backend named in the path, e.g.
+/// `/api/compile/arduino`.
+async fn compile_target(
+    target: web::Path<String>,
+    req: web::Json<CompileRequest>,
+) -> impl Responder {
+    let options = match codegen_options(&req.profile, req.non_blocking) {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+
+    match compile_to_with(&target, req.code.clone(), &options) {
+        Ok(output) => HttpResponse::Ok().json(CompileResponse { output }),
+        Err(e) => HttpResponse::BadRequest().json(ErrorResponse::from(e)),
+    }
+}
+
+/// The code generation options for a compile request.
+fn codegen_options(
+    profile: &Option<RobotProfile>,
+    non_blocking: bool,
+) -> Result<CodegenOptions, ErrorResponse> {
+    Ok(CodegenOptions {
+        profile: validate_profile(profile)?,
+        non_blocking,
+    })
+}
+
+/// The requested profile, or the default chassis when there is none.
+fn validate_profile(profile: &Option<RobotProfile>) -> Result<RobotProfile, ErrorResponse> {
+    let Some(profile) = profile else {
+        return Ok(RobotProfile::default());
+    };
+    match profile.validate() {
+        Ok(()) => Ok(profile.clone()),
+        Err(e) => Err(ErrorResponse {
             error: e.to_string(),
+            diagnostics: Vec::new(),
         }),
     }
 }
 
-async fn compile_arduino(req: web::Json<CompileRequest>) -> impl Responder {
-    match compile_to_arduino(req.code.clone()) {
-        Ok(output) => HttpResponse::Ok().json(CompileResponse { output }),
-        Err(e) => HttpResponse::BadRequest().json(ErrorResponse {
+/// The options for simulating or debugging a request, within the server's
+/// limits.
+fn simulation_options(req: &SimulateRequest) -> Result<SimulationOptions, ErrorResponse> {
+    let profile = validate_profile(&req.profile)?;
+    if let Some(Err(e)) = req.arena.as_ref().map(Arena::validate) {
+        return Err(ErrorResponse {
             error: e.to_string(),
+            diagnostics: Vec::new(),
+        });
+    }
+    Ok(SimulationOptions {
+        profile,
+        start: req.start,
+        arena: req.arena.clone(),
+        loop_main: req.loop_main,
+        max_duration_ms: req
+            .max_duration_ms
+            .unwrap_or(MAX_SIMULATED_MS)
+            .min(MAX_SIMULATED_MS),
+        max_steps: req
+            .max_steps
+            .unwrap_or(MAX_SIMULATED_STEPS)
+            .min(MAX_SIMULATED_STEPS),
+        sample_ms: req
+            .sample_ms
+            .unwrap_or(SimulationOptions::default().sample_ms)
+            .max(MIN_SAMPLE_MS),
+        max_keyframes: req
+            .max_keyframes
+            .unwrap_or(MAX_KEYFRAMES)
+            .min(MAX_KEYFRAMES),
+    })
+}
+
+/// Compiles the code and runs it on the simulated robot within the
+/// server's limits.
+async fn run_simulation(req: SimulateRequest) -> Result<Simulation, HttpResponse> {
+    let options = simulation_options(&req).map_err(|e| HttpResponse::BadRequest().json(e))?;
+
+    // A long run is CPU-bound, so keep it off the worker's event loop
+    match web::block(move || simulate(req.code, &options)).await {
+        Ok(Ok(simulation)) => Ok(simulation),
+        Ok(Err(e)) => Err(HttpResponse::BadRequest().json(ErrorResponse::from(e))),
+        Err(e) => Err(HttpResponse::InternalServerError().json(ErrorResponse {
+            error: format!("Simulation failed: {}", e),
+            diagnostics: Vec::new(),
+        })),
+    }
+}
+
+/// Returns the trace of the simulated robot's poses as keyframes.
+async fn simulate_code(req: web::Json<SimulateRequest>) -> impl Responder {
+    match run_simulation(req.into_inner()).await {
+        Ok(simulation) => HttpResponse::Ok().json(simulation),
+        Err(response) => response,
+    }
+}
+
+/// Draws the simulated path as an image in the format named in the path,
+/// `/api/simulate/svg` or `/api/simulate/png`.
+async fn render_simulation(
+    format: web::Path<String>,
+    req: web::Json<SimulateRequest>,
+) -> impl Responder {
+    if !matches!(format.as_str(), "svg" | "png") {
+        return HttpResponse::NotFound().json(ErrorResponse {
+            error: format!("Unknown image format '{}', expected svg or png", format),
+            diagnostics: Vec::new(),
+        });
+    }
+    let req = req.into_inner();
+    let options = RenderOptions {
+        arena: req.arena.clone(),
+        ..RenderOptions::default()
+    };
+    let simulation = match run_simulation(req).await {
+        Ok(simulation) => simulation,
+        Err(response) => return response,
+    };
+
+    if format.as_str() == "svg" {
+        return HttpResponse::Ok()
+            .content_type("image/svg+xml")
+            .body(render_svg(&simulation, &options));
+    }
+    let png = web::block(move || render_png(&simulation, &options)).await;
+    match png.map_err(|e| e.to_string()).and_then(|png| png) {
+        Ok(png) => HttpResponse::Ok().content_type("image/png").body(png),
+        Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
+            error: format!("Rendering failed: {}", e),
+            diagnostics: Vec::new(),
         }),
     }
 }
 
+/// Opens a debugging session over a WebSocket at `/api/debug`.
+///
+/// Each text message is a JSON command, answered by one `DebugEvent`. The
+/// first is `{"command": "launch", ...}` with the fields of a simulate
+/// request, which compiles the code and pauses on entry to `main`; another
+/// launch restarts the session. After that come the `DebugRequest`
+/// commands, such as `{"command": "step_over"}`.
+async fn debug_session(
+    req: HttpRequest,
+    body: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, messages) = actix_ws::handle(&req, body)?;
+    let mut messages = messages.aggregate_continuations();
+
+    actix_web::rt::spawn(async move {
+        let mut debugger = None;
+        while let Some(Ok(message)) = messages.recv().await {
+            let text = match message {
+                AggregatedMessage::Text(text) => text,
+                AggregatedMessage::Ping(bytes) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                AggregatedMessage::Close(_) => break,
+                _ => continue,
+            };
+            let (event, next) = debug_command(&text, debugger.take()).await;
+            debugger = next;
+            let event = serde_json::to_string(&event).expect("debug events serialize to JSON");
+            if session.text(event).await.is_err() {
+                return;
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+/// Carries out one command of a debugging session, returning the answer
+/// and the session's debugger afterwards.
+async fn debug_command(text: &str, debugger: Option<Debugger>) -> (DebugEvent, Option<Debugger>) {
+    let message: serde_json::Value = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(e) => return (debug_error(format!("Invalid command: {}", e)), debugger),
+    };
+
+    if message["command"] == "launch" {
+        let options = serde_json::from_value::<SimulateRequest>(message)
+            .map_err(|e| debug_error(format!("Invalid launch: {}", e)))
+            .and_then(|req| {
+                let options = simulation_options(&req).map_err(|e| debug_error(e.error))?;
+                Ok((req.code, options))
+            });
+        let (code, options) = match options {
+            Ok(options) => options,
+            Err(event) => return (event, debugger),
+        };
+        let launched = web::block(move || {
+            debug(code, &options).map(|mut debugger| (debugger.entry(), debugger))
+        })
+        .await;
+        return match launched {
+            Ok(Ok((event, debugger))) => (event, Some(debugger)),
+            Ok(Err(e)) => {
+                let error = ErrorResponse::from(e);
+                let event = DebugEvent::Error {
+                    message: error.error,
+                    diagnostics: error.diagnostics,
+                };
+                (event, debugger)
+            }
+            Err(e) => (debug_error(format!("Launch failed: {}", e)), debugger),
+        };
+    }
+
+    let request: DebugRequest = match serde_json::from_value(message) {
+        Ok(request) => request,
+        Err(e) => return (debug_error(format!("Invalid command: {}", e)), debugger),
+    };
+    let Some(mut debugger) = debugger else {
+        return (debug_error("Send a launch command first".to_string()), None);
+    };
+    // Continuing can run up to the step limit, so keep it off the event loop
+    match web::block(move || (debugger.handle(request), debugger)).await {
+        Ok((event, debugger)) => (event, Some(debugger)),
+        Err(e) => (debug_error(format!("Debugger failed: {}", e)), None),
+    }
+}
+
+fn debug_error(message: String) -> DebugEvent {
+    DebugEvent::Error {
+        message,
+        diagnostics: Vec::new(),
+    }
+}
+
+/// Lowers stored JSON IR with the backend named in the path, e.g.
+/// `/api/ir/arduino`, taking the same options as `/api/compile/{target}`.
+async fn compile_ir_target(
+    target: web::Path<String>,
+    req: web::Json<CompileIrRequest>,
+) -> impl Responder {
+    let options = match codegen_options(&req.profile, req.non_blocking) {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+
+    match compile_ir_to_with(&target, req.ir.clone(), &options) {
+        Ok(output) => HttpResponse::Ok().json(CompileResponse { output }),
+        Err(e) => HttpResponse::BadRequest().json(ErrorResponse::from(e)),
+    }
+}
+
+async fn list_targets() -> impl Responder {
+    let targets: Vec<TargetInfo> = TARGETS
+        .iter()
+        .filter_map(|name| backend(name, &CodegenOptions::default()))
+        .map(|backend| TargetInfo {
+            name: backend.name().to_string(),
+            file_extension: backend.file_extension().to_string(),
+        })
+        .collect();
+    HttpResponse::Ok().json(targets)
+}
+
+async fn get_ir_schema() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/schema+json")
+        .body(ir_schema())
+}
+
 async fn upload_arduino(req: web::Json<UploadRequest>) -> impl Responder {
     // Create a temporary directory for the sketch
     let temp_dir = match tempdir() {
@@ -53,6 +391,7 @@ async fn upload_arduino(req: web::Json<UploadRequest>) -> impl Responder {
         Err(e) => {
             return HttpResponse::InternalServerError().json(ErrorResponse {
                 error: format!("Failed to create temporary directory: {}", e),
+                diagnostics: Vec::new(),
             })
         }
     };
@@ -62,6 +401,7 @@ async fn upload_arduino(req: web::Json<UploadRequest>) -> impl Responder {
     if let Err(e) = std::fs::create_dir(&sketch_dir) {
         return HttpResponse::InternalServerError().json(ErrorResponse {
             error: format!("Failed to create sketch directory: {}", e),
+            diagnostics: Vec::new(),
         });
     }
 
@@ -70,6 +410,7 @@ async fn upload_arduino(req: web::Json<UploadRequest>) -> impl Responder {
     if let Err(e) = std::fs::write(&sketch_path, &req.code) {
         return HttpResponse::InternalServerError().json(ErrorResponse {
             error: format!("Failed to write sketch file: {}", e),
+            diagnostics: Vec::new(),
         });
     }
 
@@ -85,11 +426,13 @@ async fn upload_arduino(req: web::Json<UploadRequest>) -> impl Responder {
         Ok(output) if !output.status.success() => {
             return HttpResponse::BadRequest().json(ErrorResponse {
                 error: String::from_utf8_lossy(&output.stderr).to_string(),
+                diagnostics: Vec::new(),
             });
         }
         Err(e) => {
             return HttpResponse::InternalServerError().json(ErrorResponse {
                 error: format!("Failed to compile: {}", e),
+                diagnostics: Vec::new(),
             });
         }
         _ => {}
@@ -111,9 +454,11 @@ async fn upload_arduino(req: web::Json<UploadRequest>) -> impl Responder {
         }),
         Ok(output) => HttpResponse::BadRequest().json(ErrorResponse {
             error: String::from_utf8_lossy(&output.stderr).to_string(),
+            diagnostics: Vec::new(),
         }),
         Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
             error: format!("Failed to upload: {}", e),
+            diagnostics: Vec::new(),
         }),
     }
 }
@@ -138,7 +483,13 @@ async fn main() -> std::io::Result<()> {
         App::new().wrap(cors).service(
             web::scope("/api")
                 .route("/compile", web::post().to(compile_ir))
-                .route("/compile/arduino", web::post().to(compile_arduino))
+                .route("/compile/{target}", web::post().to(compile_target))
+                .route("/debug", web::get().to(debug_session))
+                .route("/ir/schema", web::get().to(get_ir_schema))
+                .route("/ir/{target}", web::post().to(compile_ir_target))
+                .route("/simulate", web::post().to(simulate_code))
+                .route("/simulate/{format}", web::post().to(render_simulation))
+                .route("/targets", web::get().to(list_targets))
                 .route("/upload/arduino", web::post().to(upload_arduino)),
         )
     })